@@ -30,6 +30,23 @@ pub fn get_app() -> App<'static, 'static> {
                         .takes_value(true)
                         .value_name("CONFIG"),
                 )
+                .arg(
+                    Arg::with_name("profile")
+                        .help("Set the profile to apply on the config (silent, balanced, performance or a custom one)")
+                        .short("p")
+                        .long("profile")
+                        .takes_value(true)
+                        .value_name("PROFILE"),
+                )
+                .arg(
+                    Arg::with_name("temp_sources")
+                        .help("Set the temperature source of each fan (CPU, GPU, NVME, ACPI, Aggregate or chip/label)")
+                        .short("t")
+                        .long("temp-sources")
+                        .takes_value(true)
+                        .multiple(true)
+                        .value_name("TEMP_SOURCES"),
+                )
                 .arg(
                     Arg::with_name("auto")
                         .help("Enable automatic speed management")
@@ -50,9 +67,22 @@ pub fn get_app() -> App<'static, 'static> {
                 .about("Get a value")
                 .setting(AppSettings::SubcommandRequiredElseHelp)
                 .subcommand(SubCommand::with_name("speeds").about("Get the fans speeds"))
+                .subcommand(
+                    SubCommand::with_name("health")
+                        .about("Get whether the fans follow the requested speeds"),
+                )
                 .subcommand(SubCommand::with_name("target-speeds").about("Get the target speeds"))
                 .subcommand(SubCommand::with_name("temps").about("Get the temperatures"))
+                .subcommand(
+                    SubCommand::with_name("sensors")
+                        .about("Get the temperature of every sensor with its limits"),
+                )
+                .subcommand(
+                    SubCommand::with_name("temp-sources")
+                        .about("Get the temperature source of each fan"),
+                )
                 .subcommand(SubCommand::with_name("config").about("Get the current config"))
+                .subcommand(SubCommand::with_name("profile").about("Get the current profile"))
                 .subcommand(SubCommand::with_name("auto").about("Get auto-handle state"))
                 .subcommand(SubCommand::with_name("status").about("Get summary")),
         )
@@ -34,6 +34,19 @@ fn main() -> Result<(), anyhow::Error> {
                 println!("{}: {:.1}%", name, speed);
             }
         }
+        if matches.is_present("health") || matches.is_present("status") {
+            if matches.is_present("status") {
+                println!("\nFans health");
+            }
+            let health = proxy.fans_health()?;
+            let names = proxy.fans_names()?;
+            for (name, health) in names.iter().zip(health) {
+                println!("{}: {}", name, health);
+            }
+            if proxy.degraded()? {
+                println!("Degraded mode: {}", proxy.fault()?);
+            }
+        }
         if matches.is_present("target-speeds") || matches.is_present("status") {
             if matches.is_present("status") {
                 println!("\nTarget speeds");
@@ -51,6 +64,14 @@ fn main() -> Result<(), anyhow::Error> {
             let config = proxy.config()?;
             println!("{}", config);
         }
+        if matches.is_present("profile") || matches.is_present("status") {
+            if matches.is_present("status") {
+                print!("\nProfile: ");
+            }
+            let profile = proxy.profile()?;
+            let reason = proxy.profile_reason()?;
+            println!("{} ({})", profile, reason);
+        }
         if matches.is_present("auto") || matches.is_present("status") {
             if matches.is_present("status") {
                 print!("\nAuto-select thresholds: ");
@@ -63,8 +84,41 @@ fn main() -> Result<(), anyhow::Error> {
                 println!("\nTemperatures");
             }
             let temps = proxy.temperatures()?;
+            let filtered_temps = proxy.filtered_temperatures()?;
             for (sensor, temp) in temps {
-                println!("{}: {:.1}°C", sensor, temp);
+                match filtered_temps.get(&sensor) {
+                    Some(filtered) if (filtered - temp).abs() >= 0.05 => {
+                        println!("{}: {:.1}°C (filtered: {:.1}°C)", sensor, temp, filtered)
+                    }
+                    _ => println!("{}: {:.1}°C", sensor, temp),
+                }
+            }
+            println!("CPU temperature source: {}", proxy.cpu_temp_source()?);
+        }
+        if matches.is_present("sensors") {
+            let faults = proxy.sensor_faults()?;
+            for (name, _chip, _label, temp, max, crit) in proxy.sensors()? {
+                let limits: Vec<String> = [("max", max), ("crit", crit)]
+                    .iter()
+                    .filter(|(_, l)| !l.is_nan())
+                    .map(|(kind, l)| format!("{}: {:.1}°C", kind, l))
+                    .chain(faults.get(&name).map(|f| format!("rejected: {}", f)))
+                    .collect();
+                if limits.is_empty() {
+                    println!("{}: {:.1}°C", name, temp);
+                } else {
+                    println!("{}: {:.1}°C ({})", name, temp, limits.join(", "));
+                }
+            }
+        }
+        if matches.is_present("temp-sources") || matches.is_present("status") {
+            if matches.is_present("status") {
+                println!("\nTemperature sources");
+            }
+            let sources = proxy.fans_temp_sources()?;
+            let names = proxy.fans_names()?;
+            for (source, name) in sources.iter().zip(names) {
+                println!("{}: {}", name, source);
             }
         }
     } else if let Some(matches) = matches.subcommand_matches("list") {
@@ -122,10 +176,22 @@ fn main() -> Result<(), anyhow::Error> {
             }
         }
 
+        if let Some(sources) = matches.values_of("temp_sources") {
+            let mut fans_sources = proxy.fans_temp_sources()?;
+            for (fan_source, source) in fans_sources.iter_mut().zip(sources) {
+                *fan_source = source.to_owned();
+            }
+            proxy.set_fans_temp_sources(fans_sources)?;
+        }
+
         if let Some(config) = matches.value_of("config") {
             proxy.set_config(config.to_owned())?;
         }
 
+        if let Some(profile) = matches.value_of("profile") {
+            proxy.set_profile(profile.to_owned())?;
+        }
+
         if matches.is_present("auto") {
             proxy.set_auto(true)?;
         } else if matches.is_present("manual") {
@@ -18,6 +18,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     )?;
 
     let mut file = std::fs::File::create("src/bus/interfaces.rs")?;
+    file.write_all("#![allow(clippy::type_complexity)]\n".as_bytes())?;
     file.write_all(interface_code.as_bytes())?;
 
     Ok(())
@@ -5,7 +5,10 @@ use dbus::blocking::LocalConnection;
 use dbus_tree::{DataType, Factory, MethodErr};
 
 use super::interfaces::*;
-use crate::constants::{BUS_NAME_STR, OBJ_PATH_STR};
+use crate::config::profile::Profile;
+use crate::config::service::{ControlMode, PidParameters, TempSource};
+use crate::constants::{BUS_NAME_STR, OBJ_PATH_STR, PROFILES_DIR_PATH};
+use crate::state::ProfileReason;
 use crate::State;
 
 use std::borrow::Borrow;
@@ -72,14 +75,46 @@ impl ComMusikidFancy for State {
             Ok(_) => {
                 let old_config = Some(self.config.replace(value));
                 self.old_config.replace(old_config);
+                self.profile_reason.replace(ProfileReason::Manual);
                 Ok(())
             }
             Err(e) => Err(MethodErr::failed(&e.to_string())),
         }
     }
+    fn profile(&self) -> IFaceResult<String> {
+        Ok(self.profile.borrow().to_owned())
+    }
+    fn set_profile(&self, value: String) -> IFaceResult<()> {
+        let profile = Profile::load(&*PROFILES_DIR_PATH, &value)
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+        let old_profile = (
+            self.profile.replace(value),
+            self.active_profile.replace(profile),
+            self.profile_reason.replace(ProfileReason::Manual),
+        );
+        self.old_profile.replace(Some(old_profile));
+        Ok(())
+    }
+    fn profile_reason(&self) -> IFaceResult<String> {
+        Ok(self.profile_reason.borrow().to_string())
+    }
+    fn active_schedule(&self) -> IFaceResult<String> {
+        Ok(self
+            .active_schedule
+            .borrow()
+            .as_ref()
+            .map(|e| e.to_string())
+            .unwrap_or_default())
+    }
     fn critical(&self) -> Result<bool, MethodErr> {
         Ok(*self.critical.borrow())
     }
+    fn degraded(&self) -> IFaceResult<bool> {
+        Ok(*self.degraded.borrow())
+    }
+    fn fault(&self) -> IFaceResult<String> {
+        Ok(self.fault.borrow().to_owned())
+    }
     fn auto(&self) -> Result<bool, MethodErr> {
         Ok(*self.auto.borrow())
     }
@@ -90,12 +125,218 @@ impl ComMusikidFancy for State {
     fn temperatures(&self) -> Result<HashMap<String, f64>, MethodErr> {
         Ok(self.temps.borrow().to_owned())
     }
+    fn filtered_temperatures(&self) -> IFaceResult<HashMap<String, f64>> {
+        Ok(self.filtered_temps.borrow().to_owned())
+    }
+    fn sensors(&self) -> IFaceResult<Vec<(String, String, String, f64, f64, f64)>> {
+        let mut registry = self.sensor_registry.borrow_mut();
+        let calibration = self.calibration.borrow();
+        registry
+            .refresh()
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        Ok(registry
+            .read(|_| true)
+            .into_iter()
+            .map(|(s, temp)| {
+                (
+                    s.name.to_owned(),
+                    s.chip.to_owned(),
+                    s.label.to_owned().unwrap_or_default(),
+                    calibration.get(&s.name).map_or(temp, |c| c.apply(temp)),
+                    s.max.unwrap_or(f64::NAN),
+                    s.crit.unwrap_or(f64::NAN),
+                )
+            })
+            .collect())
+    }
+    fn sensor_faults(&self) -> IFaceResult<HashMap<String, String>> {
+        Ok(self
+            .sensor_faults
+            .borrow()
+            .iter()
+            .map(|(name, fault)| (name.to_owned(), fault.to_string()))
+            .collect())
+    }
+    fn cpu_temp_source(&self) -> IFaceResult<String> {
+        Ok(self.cpu_temp_source.borrow().to_owned())
+    }
     fn poll_interval(&self) -> IFaceResult<u64> {
         Ok(*self.poll_interval.borrow())
     }
     fn fans_names(&self) -> Result<Vec<String>, dbus_tree::MethodErr> {
         Ok(self.fans_names.borrow().to_owned())
     }
+    fn fans_health(&self) -> IFaceResult<Vec<String>> {
+        Ok(self
+            .fans_health
+            .borrow()
+            .iter()
+            .map(|h| h.to_string())
+            .collect())
+    }
+    fn write_counters(&self) -> IFaceResult<HashMap<String, u64>> {
+        let counters = self.write_counters.borrow();
+        Ok([
+            ("verifications", counters.verifications),
+            ("speed_overwrites", counters.speed_overwrites),
+            ("reinitializations", counters.reinitializations),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), *v))
+        .collect())
+    }
+    fn fans_temp_sources(&self) -> IFaceResult<Vec<String>> {
+        let fans_settings = self.fans_settings.borrow();
+        Ok(self
+            .fans_names
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| fans_settings.get(i, name).temp_source.to_string())
+            .collect())
+    }
+    fn set_fans_temp_sources(&self, value: Vec<String>) -> IFaceResult<()> {
+        let fans_names = self.fans_names.borrow();
+        if value.len() != fans_names.len() {
+            return Err(MethodErr::invalid_arg(
+                "The number of values is not equal to the number of fans.",
+            ));
+        }
+        let sources = value
+            .iter()
+            .map(|s| s.parse::<TempSource>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| MethodErr::invalid_arg(&e.to_string()))?;
+
+        let mut fans_settings = self.fans_settings.borrow_mut();
+        for (i, (name, source)) in fans_names.iter().zip(sources).enumerate() {
+            fans_settings.entry(i, name).temp_source = source;
+        }
+        Ok(())
+    }
+    fn fans_control_modes(&self) -> IFaceResult<Vec<String>> {
+        let fans_settings = self.fans_settings.borrow();
+        Ok(self
+            .fans_names
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| fans_settings.get(i, name).control_mode.to_string())
+            .collect())
+    }
+    fn set_fans_control_modes(&self, value: Vec<String>) -> IFaceResult<()> {
+        let fans_names = self.fans_names.borrow();
+        if value.len() != fans_names.len() {
+            return Err(MethodErr::invalid_arg(
+                "The number of values is not equal to the number of fans.",
+            ));
+        }
+        let modes = value
+            .iter()
+            .map(|s| s.parse::<ControlMode>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| MethodErr::invalid_arg(&e.to_string()))?;
+
+        let mut fans_settings = self.fans_settings.borrow_mut();
+        for (i, (name, mode)) in fans_names.iter().zip(modes).enumerate() {
+            fans_settings.entry(i, name).control_mode = mode;
+        }
+        Ok(())
+    }
+    fn fans_pid_parameters(&self) -> IFaceResult<Vec<HashMap<String, f64>>> {
+        let fans_settings = self.fans_settings.borrow();
+        Ok(self
+            .fans_names
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| pid_parameters_to_map(&fans_settings.get(i, name).pid))
+            .collect())
+    }
+    fn set_fans_pid_parameters(&self, value: Vec<HashMap<String, f64>>) -> IFaceResult<()> {
+        let fans_names = self.fans_names.borrow();
+        if value.len() != fans_names.len() {
+            return Err(MethodErr::invalid_arg(
+                "The number of values is not equal to the number of fans.",
+            ));
+        }
+        let mut fans_settings = self.fans_settings.borrow_mut();
+        let params = fans_names
+            .iter()
+            .enumerate()
+            .zip(&value)
+            .map(|((i, name), map)| {
+                let mut params = fans_settings.get(i, name).pid;
+                update_pid_parameters(&mut params, map).map(|_| params)
+            })
+            .collect::<IFaceResult<Vec<_>>>()?;
+
+        for (i, (name, params)) in fans_names.iter().zip(params).enumerate() {
+            fans_settings.entry(i, name).pid = params;
+        }
+        Ok(())
+    }
+}
+
+/// Convert the PID parameters to a dictionary keyed by their names in the service configuration.
+fn pid_parameters_to_map(p: &PidParameters) -> HashMap<String, f64> {
+    [
+        ("setpoint", p.setpoint),
+        ("kp", p.kp),
+        ("ki", p.ki),
+        ("kd", p.kd),
+        ("integral_min", p.integral_min),
+        ("integral_max", p.integral_max),
+        ("min_output", p.min_output),
+        ("max_output", p.max_output),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), *v))
+    .collect()
+}
+
+/// Update the PID parameters with the values in `map`, missing parameters being left unchanged.
+fn update_pid_parameters(p: &mut PidParameters, map: &HashMap<String, f64>) -> IFaceResult<()> {
+    for (key, &value) in map {
+        let param = match key.as_str() {
+            "setpoint" => &mut p.setpoint,
+            "kp" => &mut p.kp,
+            "ki" => &mut p.ki,
+            "kd" => &mut p.kd,
+            "integral_min" => &mut p.integral_min,
+            "integral_max" => &mut p.integral_max,
+            "min_output" => &mut p.min_output,
+            "max_output" => &mut p.max_output,
+            _ => {
+                return Err(MethodErr::invalid_arg(&format!(
+                    "`{}` is not a valid PID parameter",
+                    key
+                )))
+            }
+        };
+        if !value.is_finite() {
+            return Err(MethodErr::invalid_arg(&format!(
+                "The value of `{}` is not finite",
+                key
+            )));
+        }
+        *param = value;
+    }
+
+    if p.integral_min > p.integral_max {
+        return Err(MethodErr::invalid_arg(
+            "`integral_min` is greater than `integral_max`",
+        ));
+    }
+    if !(0f64..=100f64).contains(&p.min_output)
+        || !(0f64..=100f64).contains(&p.max_output)
+        || p.min_output > p.max_output
+    {
+        return Err(MethodErr::invalid_arg("The output bounds are invalid"));
+    }
+
+    Ok(())
 }
 
 /// Create the D-Bus connection to listen incoming requests.
@@ -124,6 +365,12 @@ pub(crate) fn create_dbus_conn(data: Rc<State>) -> Result<LocalConnection, dbus:
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::calibration::SensorFault;
+    use crate::config::service::SensorCalibration;
+    use crate::ec_control::FanHealth;
+    use crate::hwmon::SensorRegistry;
+    use crate::power::PowerSource;
+    use crate::schedule::{ScheduleEntry, TimeOfDay};
     use std::cell::RefCell;
 
     #[test]
@@ -131,6 +378,8 @@ mod tests {
         let dummy_fans_speeds = vec![50., 35., 23.];
         let dummy_target_fans_speeds = vec![90., 85., 75.];
         let dummy_temps: HashMap<String, f64> = vec![("CPU".to_owned(), 26.)].into_iter().collect();
+        let dummy_filtered_temps: HashMap<String, f64> =
+            vec![("CPU".to_owned(), 24.)].into_iter().collect();
         let dummy_config = String::from("Dummy config");
         let state = State {
             ec_access_mode: RefCell::new(crate::config::service::ECAccessMode::Either),
@@ -140,8 +389,29 @@ mod tests {
             critical: RefCell::new(false),
             config: RefCell::new(dummy_config),
             temps: RefCell::new(dummy_temps.clone()),
+            filtered_temps: RefCell::new(dummy_filtered_temps.clone()),
+            cpu_temp_source: RefCell::new("thermal_zone0".to_string()),
             poll_interval: RefCell::new(0),
             fans_names: RefCell::new(vec!["dummy".to_string()]),
+            fans_health: RefCell::new(vec![FanHealth::Stalled]),
+            degraded: RefCell::new(true),
+            fault: RefCell::new("No sensor found".to_string()),
+            calibration: RefCell::new(
+                vec![(
+                    "acpitz/temp1".to_string(),
+                    SensorCalibration {
+                        offset: Some(-5.0),
+                        scale: None,
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            sensor_faults: RefCell::new(
+                vec![("nvme/Composite".to_string(), SensorFault::Jump)]
+                    .into_iter()
+                    .collect(),
+            ),
             check_control_config: RefCell::new(false),
             ..Default::default()
         };
@@ -153,10 +423,40 @@ mod tests {
         );
         assert_eq!(&*state.config().unwrap(), "Dummy config");
         assert_eq!(state.critical().unwrap(), false);
+        assert!(state.degraded().unwrap());
+        assert_eq!(&*state.fault().unwrap(), "No sensor found");
         assert_eq!(state.auto().unwrap(), true);
         assert_eq!(state.temperatures().unwrap(), dummy_temps);
+        assert_eq!(state.filtered_temperatures().unwrap(), dummy_filtered_temps);
+        assert_eq!(&*state.cpu_temp_source().unwrap(), "thermal_zone0");
+
+        state
+            .sensor_registry
+            .replace(SensorRegistry::new("tests/hwmon"));
+        let sensors = state.sensors().unwrap();
+        assert_eq!(sensors.len(), 6);
+        assert_eq!(
+            (&*sensors[0].0, &*sensors[0].1, &*sensors[0].2),
+            ("coretemp/Package id 0", "coretemp", "Package id 0")
+        );
+        assert_eq!(
+            (sensors[0].3, sensors[0].4, sensors[0].5),
+            (45.0, 80.0, 100.0)
+        );
+        assert_eq!(&*sensors[2].2, "");
+        assert!(sensors[2].4.is_nan());
+        // The calibration is applied.
+        assert_eq!(sensors[2].3, 35.0);
+        assert_eq!(
+            state.sensor_faults().unwrap(),
+            vec![("nvme/Composite".to_string(), "Jump".to_string())]
+                .into_iter()
+                .collect()
+        );
         assert_eq!(state.poll_interval().unwrap(), 0);
         assert_eq!(state.fans_names().unwrap(), vec!["dummy".to_string()]);
+        assert_eq!(state.fans_health().unwrap(), vec!["Stalled".to_string()]);
+        assert_eq!(state.write_counters().unwrap()["verifications"], 0);
     }
 
     #[test]
@@ -202,6 +502,167 @@ mod tests {
         assert!(state.set_target_fans_speeds(invalid_number_speeds).is_err());
     }
 
+    #[test]
+    fn fans_temp_sources() {
+        let state = State {
+            fans_names: RefCell::from(vec!["CPU fan".to_string(), "GPU fan".to_string()]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.fans_temp_sources().unwrap(),
+            vec!["Aggregate".to_string(), "Aggregate".to_string()]
+        );
+
+        let sources = vec!["CPU".to_string(), "amdgpu/edge".to_string()];
+        assert!(state.set_fans_temp_sources(sources.clone()).is_ok());
+        assert_eq!(state.fans_temp_sources().unwrap(), sources);
+        assert_eq!(
+            state.fans_settings.borrow().get(1, "GPU fan").temp_source,
+            TempSource::Sensor("amdgpu/edge".to_string())
+        );
+
+        assert!(state
+            .set_fans_temp_sources(vec!["CPU".to_string(), "Invalid".to_string()])
+            .is_err());
+        assert!(state
+            .set_fans_temp_sources(vec!["CPU".to_string()])
+            .is_err());
+        assert_eq!(state.fans_temp_sources().unwrap(), sources);
+    }
+
+    #[test]
+    fn fans_control_modes() {
+        let state = State {
+            fans_names: RefCell::from(vec!["Fan #1".to_string(), "Fan #2".to_string()]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.fans_control_modes().unwrap(),
+            vec!["Threshold".to_string(), "Threshold".to_string()]
+        );
+
+        let modes = vec!["Pid".to_string(), "Curve".to_string()];
+        assert!(state.set_fans_control_modes(modes.clone()).is_ok());
+        assert_eq!(state.fans_control_modes().unwrap(), modes);
+
+        assert!(state
+            .set_fans_control_modes(vec!["Pid".to_string(), "Invalid".to_string()])
+            .is_err());
+        assert_eq!(state.fans_control_modes().unwrap(), modes);
+    }
+
+    #[test]
+    fn fans_pid_parameters() {
+        let state = State {
+            fans_names: RefCell::from(vec!["Fan #1".to_string(), "Fan #2".to_string()]),
+            ..Default::default()
+        };
+
+        let default_params = pid_parameters_to_map(&PidParameters::default());
+        assert_eq!(
+            state.fans_pid_parameters().unwrap(),
+            vec![default_params.clone(), default_params.clone()]
+        );
+
+        let changes: HashMap<String, f64> =
+            vec![("setpoint".to_string(), 70.), ("kp".to_string(), 3.)]
+                .into_iter()
+                .collect();
+        assert!(state
+            .set_fans_pid_parameters(vec![changes, HashMap::new()])
+            .is_ok());
+
+        let params = state.fans_pid_parameters().unwrap();
+        assert_eq!(params[0]["setpoint"], 70.);
+        assert_eq!(params[0]["kp"], 3.);
+        assert_eq!(params[0]["ki"], PidParameters::default().ki);
+        assert_eq!(params[1], default_params);
+
+        let invalid_key: HashMap<String, f64> =
+            vec![("invalid".to_string(), 1.)].into_iter().collect();
+        assert!(state
+            .set_fans_pid_parameters(vec![HashMap::new(), invalid_key])
+            .is_err());
+
+        let invalid_bounds: HashMap<String, f64> = vec![
+            ("min_output".to_string(), 80.),
+            ("max_output".to_string(), 20.),
+        ]
+        .into_iter()
+        .collect();
+        assert!(state
+            .set_fans_pid_parameters(vec![invalid_bounds, HashMap::new()])
+            .is_err());
+
+        assert!(state.set_fans_pid_parameters(vec![HashMap::new()]).is_err());
+        assert_eq!(state.fans_pid_parameters().unwrap(), params);
+    }
+
+    #[test]
+    fn profile() {
+        let state = State {
+            profile_reason: RefCell::new(ProfileReason::PowerSource(PowerSource::Battery)),
+            ..Default::default()
+        };
+        assert_eq!(state.profile_reason().unwrap(), "Battery");
+
+        assert!(state.set_profile("silent".to_string()).is_ok());
+        assert_eq!(state.profile().unwrap(), "silent");
+        assert_eq!(state.profile_reason().unwrap(), "Manual");
+        assert_eq!(
+            *state.active_profile.borrow(),
+            Profile::builtin("silent").unwrap()
+        );
+
+        let (old_profile, _, old_reason) = state.old_profile.borrow().clone().unwrap();
+        assert_eq!(old_profile, "");
+        assert_eq!(old_reason, ProfileReason::PowerSource(PowerSource::Battery));
+
+        assert!(state.set_profile("../silent".to_string()).is_err());
+        assert!(state.set_profile("inexistent".to_string()).is_err());
+        assert_eq!(state.profile().unwrap(), "silent");
+
+        assert!(state.set_profile(String::new()).is_ok());
+        assert_eq!(*state.active_profile.borrow(), Profile::default());
+    }
+
+    #[test]
+    fn saved_profile() {
+        let state = State {
+            profile: RefCell::new("silent".to_string()),
+            user_profile: RefCell::new("performance".to_string()),
+            profile_reason: RefCell::new(ProfileReason::PowerSource(PowerSource::Battery)),
+            config: RefCell::new("Battery config".to_string()),
+            user_config: RefCell::new("Dummy config".to_string()),
+            ..Default::default()
+        };
+
+        // The profile and the configuration applied automatically are not saved.
+        let config = state.as_service_config();
+        assert_eq!(config.profile, "performance");
+        assert_eq!(config.selected_fan_config, "Dummy config");
+    }
+
+    #[test]
+    fn active_schedule() {
+        let state = State {
+            ..Default::default()
+        };
+        assert_eq!(state.active_schedule().unwrap(), "");
+
+        state.active_schedule.replace(Some(ScheduleEntry {
+            name: "Quiet hours".to_string(),
+            days: Vec::new(),
+            start: TimeOfDay::new(9, 0),
+            end: TimeOfDay::new(18, 0),
+            profile: None,
+            max_speed: Some(40.),
+        }));
+        assert_eq!(state.active_schedule().unwrap(), "Quiet hours");
+    }
+
     #[test]
     fn out_of_bounds_target_speeds() {
         let state = State {
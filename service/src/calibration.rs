@@ -0,0 +1,206 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::{error, info};
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::service::{Plausibility, SensorCalibration};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Describe why the reading of a sensor has been rejected.
+pub(crate) enum SensorFault {
+    /// The temperature is out of the plausible range.
+    OutOfRange,
+    /// The temperature has changed too much since the last reading.
+    Jump,
+    /// The temperature has not changed for too many readings.
+    Stuck,
+}
+
+impl fmt::Display for SensorFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CheckState {
+    last_accepted: Option<f64>,
+    last_read: Option<f64>,
+    /// Number of consecutive readings with the same value.
+    repeated: u32,
+    fault: Option<SensorFault>,
+}
+
+impl CheckState {
+    fn check(&mut self, temp: f64, settings: &Plausibility) -> Result<f64, SensorFault> {
+        if self.last_read == Some(temp) {
+            self.repeated = self.repeated.saturating_add(1);
+        } else {
+            self.repeated = 0;
+        }
+
+        // A jump is accepted once it is confirmed by the next reading.
+        let close_to =
+            |last: Option<f64>| last.is_none_or(|l| (temp - l).abs() <= settings.max_jump);
+        let jump = !close_to(self.last_accepted) && !close_to(self.last_read);
+        self.last_read = Some(temp);
+
+        if temp < settings.min_temp || temp > settings.max_temp {
+            Err(SensorFault::OutOfRange)
+        } else if jump {
+            Err(SensorFault::Jump)
+        } else if settings.stuck_readings > 0 && self.repeated >= settings.stuck_readings {
+            Err(SensorFault::Stuck)
+        } else {
+            self.last_accepted = Some(temp);
+            Ok(temp)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Calibrates the readings of the sensors and rejects the implausible ones.
+pub(crate) struct SensorChecks {
+    states: HashMap<String, CheckState>,
+}
+
+impl SensorChecks {
+    /// Calibrate the temperature `temp` read from the sensor `name`, and check that it is plausible.
+    /// Returns `None` if the reading has been rejected,
+    /// or the last accepted temperature while a jump has not been confirmed.
+    pub fn check(
+        &mut self,
+        name: &str,
+        temp: f64,
+        calibration: Option<&SensorCalibration>,
+        plausibility: &Plausibility,
+    ) -> Option<f64> {
+        let temp = calibration.map_or(temp, |c| c.apply(temp));
+        if !plausibility.enabled {
+            return Some(temp);
+        }
+
+        let state = self.states.entry(name.to_owned()).or_default();
+        let result = state.check(temp, plausibility);
+
+        let fault = result.err();
+        if fault != state.fault {
+            match fault {
+                Some(fault) => error!(
+                    "The sensor `{}` is faulty ({}), its temperature {:.1}°C is ignored",
+                    name, fault, temp
+                ),
+                None => info!("The sensor `{}` is valid again", name),
+            }
+            state.fault = fault;
+        }
+
+        match result {
+            Ok(temp) => Some(temp),
+            // The sensor keeps being used during the jump,
+            // otherwise the readings are lost when every sensor jumps at the same time.
+            Err(SensorFault::Jump) => state.last_accepted,
+            Err(_) => None,
+        }
+    }
+
+    /// Get the faults of the sensors whose last reading has been rejected.
+    pub fn faults(&self) -> HashMap<String, SensorFault> {
+        self.states
+            .iter()
+            .filter_map(|(name, s)| s.fault.map(|f| (name.to_owned(), f)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_all(settings: &Plausibility, temps: &[f64]) -> Vec<Option<f64>> {
+        let mut checks = SensorChecks::default();
+        temps
+            .iter()
+            .map(|&t| checks.check("acpitz/temp1", t, None, settings))
+            .collect()
+    }
+
+    #[test]
+    fn out_of_range() {
+        let settings = Plausibility::default();
+        assert_eq!(
+            check_all(&settings, &[40.0, -5.0, 40.0, 255.0]),
+            vec![Some(40.0), None, Some(40.0), None]
+        );
+    }
+
+    #[test]
+    fn jumps() {
+        let settings = Plausibility {
+            max_jump: 10.0,
+            ..Default::default()
+        };
+        // A single spike is ignored, the last accepted temperature is used instead.
+        assert_eq!(
+            check_all(&settings, &[40.0, 95.0, 41.0, 42.0]),
+            vec![Some(40.0), Some(40.0), Some(41.0), Some(42.0)]
+        );
+        // A real change is accepted at the next reading.
+        assert_eq!(
+            check_all(&settings, &[40.0, 70.0, 71.0, 65.0]),
+            vec![Some(40.0), Some(40.0), Some(71.0), Some(65.0)]
+        );
+    }
+
+    #[test]
+    fn stuck() {
+        let settings = Plausibility {
+            stuck_readings: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            check_all(&settings, &[40.0, 40.0, 40.0, 41.0]),
+            vec![Some(40.0), Some(40.0), None, Some(41.0)]
+        );
+
+        // Disabled by default.
+        assert_eq!(
+            check_all(&Plausibility::default(), &[40.0; 10]),
+            vec![Some(40.0); 10]
+        );
+    }
+
+    #[test]
+    fn calibration_and_faults() {
+        let mut checks = SensorChecks::default();
+        let settings = Plausibility::default();
+        let calibration = SensorCalibration {
+            offset: Some(-27.0),
+            scale: None,
+        };
+
+        assert_eq!(
+            checks.check("k10temp/Tctl", 72.0, Some(&calibration), &settings),
+            Some(45.0)
+        );
+        assert_eq!(checks.check("acpitz/temp1", 200.0, None, &settings), None);
+        assert_eq!(
+            checks.faults(),
+            vec![("acpitz/temp1".to_owned(), SensorFault::OutOfRange)]
+                .into_iter()
+                .collect()
+        );
+
+        let disabled = Plausibility {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            checks.check("acpitz/temp1", 200.0, None, &disabled),
+            Some(200.0)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::info;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::nbfc::{FanControlConfigV2, TemperatureThreshold};
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ProfileLoadError {
+    #[snafu(display("Error occurred while trying to load profile `{}`: {}", name, source))]
+    ProfileLoading {
+        name: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error occurred while deserializing profile `{}`: {}", name, source))]
+    ProfileDeserialize {
+        name: String,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("The profile `{}` does not exist", name))]
+    InexistentProfile { name: String },
+
+    #[snafu(display("The profile name `{}` contains invalid characters", name))]
+    ProfileInvalidChars { name: String },
+
+    #[snafu(display("The profile `{}` replaces the thresholds with an empty list", name))]
+    EmptyThresholds { name: String },
+}
+
+const INVALID_CHARS: &[char] = &['.', '/'];
+
+type Result<T> = std::result::Result<T, ProfileLoadError>;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Describe the changes applied on top of the selected fan control configuration.
+pub(crate) struct Profile {
+    /// Factor applied to the speeds of the thresholds.
+    pub speed_scale: Option<f64>,
+    /// Minimum speed of the fans, in percent.
+    pub min_speed: Option<f64>,
+    /// Maximum speed of the fans, in percent.
+    pub max_speed: Option<f64>,
+    /// Temperature which triggers the critical mode.
+    pub critical_temperature: Option<u8>,
+    /// Thresholds replacing the ones of every fan.
+    pub temperature_thresholds: Option<Vec<TemperatureThreshold>>,
+}
+
+impl Profile {
+    /// Get the profile named `name` which is provided by the service.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "balanced" => Some(Profile::default()),
+            "silent" => Some(Profile {
+                speed_scale: Some(0.7),
+                max_speed: Some(70.0),
+                ..Default::default()
+            }),
+            "performance" => Some(Profile {
+                speed_scale: Some(1.3),
+                min_speed: Some(20.0),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Load the profile named `name` from `dir`, or the built-in one if there is no such file.
+    /// An empty name stands for no profile.
+    pub fn load<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self> {
+        if name.is_empty() {
+            return Ok(Profile::default());
+        }
+        ensure!(!name.contains(INVALID_CHARS), ProfileInvalidChars { name });
+
+        let path = dir.as_ref().join(name).with_extension("toml");
+        if path.is_file() {
+            info!("Loading profile '{}'", name);
+            let buf = read_to_string(path).context(ProfileLoading { name })?;
+            let profile: Profile = toml::from_str(&buf).context(ProfileDeserialize { name })?;
+            // The fans need at least one threshold.
+            ensure!(
+                profile
+                    .temperature_thresholds
+                    .as_ref()
+                    .is_none_or(|t| !t.is_empty()),
+                EmptyThresholds { name }
+            );
+            Ok(profile)
+        } else {
+            Profile::builtin(name).ok_or_else(|| InexistentProfile { name }.build())
+        }
+    }
+
+    /// Apply the profile on the fan control configuration.
+    pub fn apply(&self, config: &mut FanControlConfigV2) {
+        if let Some(critical_temperature) = self.critical_temperature {
+            config.critical_temperature = critical_temperature;
+        }
+
+        for fan in &mut config.fan_configurations {
+            if let Some(thresholds) = &self.temperature_thresholds {
+                fan.temperature_thresholds = thresholds.clone();
+            }
+
+            for threshold in &mut fan.temperature_thresholds {
+                let speed = threshold.fan_speed as f64 * self.speed_scale.unwrap_or(1.0);
+                threshold.fan_speed = self.clamp_speed(speed) as f32;
+            }
+        }
+    }
+
+    /// Limit `speed` to the bounds of the profile.
+    pub fn clamp_speed(&self, speed: f64) -> f64 {
+        let min_speed = self.min_speed.unwrap_or(0.0).clamp(0.0, 100.0);
+        let max_speed = self.max_speed.unwrap_or(100.0).clamp(min_speed, 100.0);
+        speed.clamp(min_speed, max_speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nbfc::FanConfiguration;
+
+    fn threshold(up_threshold: u8, down_threshold: u8, fan_speed: f32) -> TemperatureThreshold {
+        TemperatureThreshold {
+            up_threshold,
+            down_threshold,
+            fan_speed,
+        }
+    }
+
+    fn config() -> FanControlConfigV2 {
+        FanControlConfigV2 {
+            critical_temperature: 90,
+            fan_configurations: vec![FanConfiguration {
+                read_register: 0,
+                write_register: 0,
+                min_speed_value: 0,
+                max_speed_value: 255,
+                independent_read_min_max_values: false,
+                min_speed_value_read: 0,
+                max_speed_value_read: 0,
+                reset_required: false,
+                fan_speed_reset_value: None,
+                fan_display_name: None,
+                temperature_thresholds: vec![
+                    threshold(0, 0, 0.0),
+                    threshold(60, 50, 50.0),
+                    threshold(80, 70, 100.0),
+                ],
+                fan_speed_percentage_overrides: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn speeds(config: &FanControlConfigV2) -> Vec<f32> {
+        config.fan_configurations[0]
+            .temperature_thresholds
+            .iter()
+            .map(|t| t.fan_speed)
+            .collect()
+    }
+
+    #[test]
+    fn scale_and_caps() {
+        let mut c = config();
+        Profile {
+            speed_scale: Some(0.5),
+            min_speed: Some(10.0),
+            max_speed: Some(40.0),
+            ..Default::default()
+        }
+        .apply(&mut c);
+
+        assert_eq!(speeds(&c), vec![10.0, 25.0, 40.0]);
+        assert_eq!(c.critical_temperature, 90);
+
+        let mut c = config();
+        Profile::builtin("performance").unwrap().apply(&mut c);
+        assert_eq!(speeds(&c), vec![20.0, 65.0, 100.0]);
+    }
+
+    #[test]
+    fn replace_thresholds() {
+        let mut c = config();
+        Profile {
+            critical_temperature: Some(85),
+            temperature_thresholds: Some(vec![threshold(0, 0, 30.0), threshold(70, 60, 80.0)]),
+            ..Default::default()
+        }
+        .apply(&mut c);
+
+        assert_eq!(speeds(&c), vec![30.0, 80.0]);
+        assert_eq!(c.critical_temperature, 85);
+
+        let mut c = config();
+        Profile::builtin("balanced").unwrap().apply(&mut c);
+        assert_eq!(speeds(&c), speeds(&config()));
+    }
+
+    #[test]
+    fn load() {
+        let profile = Profile::load("tests/profiles", "custom").unwrap();
+        assert_eq!(profile.max_speed, Some(60.0));
+        assert_eq!(profile.critical_temperature, Some(85));
+        assert_eq!(profile.temperature_thresholds.unwrap().len(), 2);
+
+        assert_eq!(
+            Profile::load("tests/profiles", "silent").unwrap(),
+            Profile::builtin("silent").unwrap()
+        );
+        assert_eq!(
+            Profile::load("tests/profiles", "").unwrap(),
+            Profile::default()
+        );
+
+        assert!(matches!(
+            Profile::load("tests/profiles", "inexistent"),
+            Err(ProfileLoadError::InexistentProfile { .. })
+        ));
+        assert!(matches!(
+            Profile::load("tests/profiles", "../profiles/custom"),
+            Err(ProfileLoadError::ProfileInvalidChars { .. })
+        ));
+        assert!(matches!(
+            Profile::load("tests/profiles", "empty"),
+            Err(ProfileLoadError::EmptyThresholds { .. })
+        ));
+    }
+}
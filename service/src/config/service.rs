@@ -6,16 +6,21 @@ use quick_xml::de::from_str as xml_from_str;
 use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
 
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use crate::constants::ROOT_CONFIG_PATH;
-use crate::nbfc::NbfcServiceSettings;
+use crate::ec_control::{PortWait, DEFAULT_COMMAND_PORT, DEFAULT_DATA_PORT, DEFAULT_PORT_TIMEOUT};
+use crate::nbfc::{NbfcServiceSettings, TemperatureThreshold};
+use crate::power::PowerSource;
+use crate::schedule::ScheduleEntry;
 
-static EC_SYS_DEV_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/sys/kernel/debug/ec/ec0/io"));
-static ACPI_EC_DEV_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/dev/ec"));
-static PORT_DEV_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/dev/port"));
 static CONFIG_FILE_PATH: Lazy<PathBuf> = Lazy::new(|| ROOT_CONFIG_PATH.join("config.toml"));
 static NBFC_SETTINGS_PATH: Lazy<&Path> =
     Lazy::new(|| Path::new("/etc/NbfcService/NbfcServiceSettings.xml"));
@@ -29,33 +34,37 @@ pub(crate) enum ECAccessMode {
     AcpiEC,
     /// Access to the EC using the module `ec_sys` with `write_support=1`.
     ECSys,
+    /// Call the ACPI methods given in `acpi_call` with the module `acpi_call` (`/proc/acpi/call`).
+    AcpiCall,
     /// Determine the way to access to the EC at run.
     Either,
 }
 
 /// Get the device path from `ECAccessMode`.
 impl ECAccessMode {
-    /// Get path corresponding to the access mode.
-    ///
-    /// # Panics
-    ///
-    /// Panic if the value is [Either](#enum.ECAccessMode) and it's not possible to get an access to the EC.
-    pub fn to_path(&self) -> &'static Path {
+    /// Get path corresponding to the access mode in `devices`.
+    /// There is no EC device for `AcpiCall`, and `Either` has to be probed at run.
+    pub fn to_path(self, devices: &ECDevices) -> Option<&Path> {
         match self {
-            ECAccessMode::RawPort => *PORT_DEV_PATH,
-            ECAccessMode::AcpiEC => *ACPI_EC_DEV_PATH,
-            ECAccessMode::ECSys => *EC_SYS_DEV_PATH,
-            ECAccessMode::Either => {
-                if PORT_DEV_PATH.exists() {
-                    *PORT_DEV_PATH
-                } else if ACPI_EC_DEV_PATH.exists() {
-                    *ACPI_EC_DEV_PATH
-                } else if EC_SYS_DEV_PATH.exists() {
-                    *EC_SYS_DEV_PATH
-                } else {
-                    panic!("No module for access to the EC is available")
-                }
-            }
+            ECAccessMode::RawPort => Some(&devices.raw_port),
+            ECAccessMode::AcpiEC => Some(&devices.acpi_ec),
+            ECAccessMode::ECSys => Some(&devices.ec_sys),
+            ECAccessMode::AcpiCall | ECAccessMode::Either => None,
+        }
+    }
+
+    /// Get the access modes to try, in order of preference.
+    pub fn candidates(&self) -> &'static [ECAccessMode] {
+        match self {
+            ECAccessMode::RawPort => &[ECAccessMode::RawPort],
+            ECAccessMode::AcpiEC => &[ECAccessMode::AcpiEC],
+            ECAccessMode::ECSys => &[ECAccessMode::ECSys],
+            ECAccessMode::AcpiCall => &[ECAccessMode::AcpiCall],
+            ECAccessMode::Either => &[
+                ECAccessMode::RawPort,
+                ECAccessMode::AcpiEC,
+                ECAccessMode::ECSys,
+            ],
         }
     }
 }
@@ -64,24 +73,161 @@ impl Default for ECAccessMode {
         ECAccessMode::Either
     }
 }
-impl From<&Path> for ECAccessMode {
-    fn from(s: &Path) -> Self {
-        match s {
-            _s if _s == *PORT_DEV_PATH => ECAccessMode::RawPort,
-            _s if _s == *ACPI_EC_DEV_PATH => ECAccessMode::AcpiEC,
-            _s if _s == *EC_SYS_DEV_PATH => ECAccessMode::ECSys,
-            _ => unreachable!(),
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Describe the devices used to access to an EC.
+pub(crate) struct ECDevices {
+    /// Device of the `RawPort` access mode.
+    pub raw_port: PathBuf,
+    /// Device of the `AcpiEC` access mode.
+    pub acpi_ec: PathBuf,
+    /// Device of the `ECSys` access mode (e.g. `/sys/kernel/debug/ec/ec1/io` for a second EC).
+    pub ec_sys: PathBuf,
+    /// Command port of the EC with the `RawPort` access mode.
+    pub command_port: u16,
+    /// Data port of the EC with the `RawPort` access mode.
+    pub data_port: u16,
+    /// Time (in milliseconds) after which a wait for the EC fails with the `RawPort` access mode.
+    pub port_timeout: u64,
+    /// Sleep (in microseconds) between two checks of the status of the EC with the `RawPort`
+    /// access mode, doubled after each check up to `port_max_sleep`.
+    /// The status is checked continuously if it is 0.
+    pub port_sleep: u64,
+    pub port_max_sleep: u64,
+}
+
+impl ECDevices {
+    /// Check whether the EC accessed with `mode` may be the one accessed with `other_mode`
+    /// through the `other` devices, because they have a device (and the ports) in common.
+    pub fn shares_ec(
+        &self,
+        mode: ECAccessMode,
+        other: &ECDevices,
+        other_mode: ECAccessMode,
+    ) -> bool {
+        mode.candidates()
+            .iter()
+            .filter(|m| other_mode.candidates().contains(m))
+            .any(|&m| match m {
+                ECAccessMode::RawPort => {
+                    self.raw_port == other.raw_port
+                        && self.command_port == other.command_port
+                        && self.data_port == other.data_port
+                }
+                ECAccessMode::AcpiEC | ECAccessMode::ECSys => m.to_path(self) == m.to_path(other),
+                ECAccessMode::AcpiCall | ECAccessMode::Either => false,
+            })
+    }
+
+    /// Get how to wait for the EC with the `RawPort` access mode.
+    pub fn port_wait(&self) -> PortWait {
+        PortWait {
+            timeout: Duration::from_millis(self.port_timeout),
+            sleep: Duration::from_micros(self.port_sleep),
+            max_sleep: Duration::from_micros(self.port_max_sleep),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+impl Default for ECDevices {
+    fn default() -> Self {
+        ECDevices {
+            raw_port: PathBuf::from("/dev/port"),
+            acpi_ec: PathBuf::from("/dev/ec"),
+            ec_sys: PathBuf::from("/sys/kernel/debug/ec/ec0/io"),
+            command_port: DEFAULT_COMMAND_PORT,
+            data_port: DEFAULT_DATA_PORT,
+            port_timeout: DEFAULT_PORT_TIMEOUT.as_millis() as u64,
+            port_sleep: 0,
+            port_max_sleep: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Describe an EC which controls some of the fans, in addition to the main one.
+/// The main EC controls the other fans and holds the registers written at the initialization.
+pub(crate) struct SecondaryEC {
+    #[serde(default)]
+    pub access_mode: ECAccessMode,
+    /// Indices of the fans of the fan control configuration which are controlled by this EC.
+    pub fans: Vec<usize>,
+    /// Devices of the EC, which have to differ from the default ones and from the other ECs.
+    #[serde(default)]
+    pub devices: ECDevices,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+/// Describe how the fans are controlled.
+pub(crate) enum FanBackendSettings {
+    /// Write the speeds to the registers of the EC, which is accessed according to `ec_access_mode`.
+    #[default]
+    Ec,
+    /// Write the speeds to the `pwmN` files of a hwmon chip, provided by a kernel driver.
+    Hwmon {
+        /// Name of the chip (e.g. `thinkpad`, `dell_smm` or `asus`).
+        chip: String,
+        /// Index `N` of the `pwmN` file of each fan of the fan control configuration
+        /// (`1`, `2`... by default).
+        #[serde(default)]
+        pwms: Vec<u32>,
+        /// Speed of the fans (in RPM) at full speed, used to read their speed from `fanN_input`.
+        /// The PWM value is read back when it is not set.
+        max_rpm: Option<u32>,
+    },
+    /// Set the levels of the fan of the ThinkPads with `/proc/acpi/ibm/fan`,
+    /// which requires `thinkpad_acpi` to be loaded with `fan_control=1`.
+    Thinkpad {
+        /// Delay (in seconds, between 1 and 120) after which the firmware takes the control back
+        /// if the service stops writing (120 by default).
+        watchdog: Option<u32>,
+        /// Speed of the fan (in RPM) at full speed, used to read its speed.
+        /// The speed of the level is used when it is not set.
+        max_rpm: Option<u32>,
+        /// Thresholds of the fan when no fan control configuration is selected
+        /// (the default ones of the backend when it is not set).
+        temperature_thresholds: Option<Vec<TemperatureThreshold>>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Describe the ACPI methods called with the `AcpiCall` access mode.
+/// The methods are given with their path and their arguments (e.g. `\_SB.PCI0.LPCB.EC0.SFAN 0x1`).
+pub(crate) struct AcpiCallSettings {
+    /// Method called to take the control of the fans.
+    pub init: Option<String>,
+    /// Method called to give the control of the fans back to the firmware.
+    pub reset: Option<String>,
+    /// Methods of each fan of the fan control configuration.
+    pub fans: Vec<AcpiCallFan>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Describe the ACPI methods of a fan.
+/// The speed values are mapped with the values of the fan control configuration.
+pub(crate) struct AcpiCallFan {
+    /// Method called with the speed value as last argument.
+    pub write: String,
+    /// Method which returns the speed value.
+    /// The last written speed is used when it is not set.
+    pub read: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 /// Describe how to get the temperature.
 pub(crate) enum TempComputeMethod {
     /// Get the CPU sensor data only.
     CPUOnly,
     /// Compute the average from all valid sensors.
     AllSensors,
+    /// Get the highest temperature of all valid sensors.
+    Max,
+    /// Compute the average of the sources weighted with `temp_weights`.
+    WeightedAverage,
+    /// Get the temperature of the sensor which is the closest to its critical temperature.
+    RelativeToCritical,
 }
 impl Default for TempComputeMethod {
     fn default() -> Self {
@@ -89,6 +235,461 @@ impl Default for TempComputeMethod {
     }
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+/// Describe the temperature which drives a fan.
+pub(crate) enum TempSource {
+    /// Temperature computed with the [TempComputeMethod](#enum.TempComputeMethod).
+    #[default]
+    Aggregate,
+    /// Average of the CPU sensors.
+    Cpu,
+    /// Average of the GPU sensors.
+    Gpu,
+    /// Average of the NVME sensors.
+    Nvme,
+    /// Average of the ACPI sensors.
+    Acpi,
+    /// A specific hwmon sensor, identified by `chip/label` (e.g. `coretemp/Core 0`).
+    Sensor(String),
+}
+impl fmt::Display for TempSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TempSource::Aggregate => f.write_str("Aggregate"),
+            TempSource::Cpu => f.write_str("CPU"),
+            TempSource::Gpu => f.write_str("GPU"),
+            TempSource::Nvme => f.write_str("NVME"),
+            TempSource::Acpi => f.write_str("ACPI"),
+            TempSource::Sensor(name) => f.write_str(name),
+        }
+    }
+}
+impl FromStr for TempSource {
+    type Err = TempSourceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Aggregate" => Ok(TempSource::Aggregate),
+            "CPU" => Ok(TempSource::Cpu),
+            "GPU" => Ok(TempSource::Gpu),
+            "NVME" => Ok(TempSource::Nvme),
+            "ACPI" => Ok(TempSource::Acpi),
+            s if s.contains('/') => Ok(TempSource::Sensor(s.to_owned())),
+            _ => Err(TempSourceParseError::UnknownSource { name: s.to_owned() }),
+        }
+    }
+}
+impl TryFrom<String> for TempSource {
+    type Error = TempSourceParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl From<TempSource> for String {
+    fn from(s: TempSource) -> Self {
+        s.to_string()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum TempSourceParseError {
+    #[snafu(display(
+        "`{}` is not a valid temperature source (expected CPU, GPU, NVME, ACPI, Aggregate or `chip/label`)",
+        name
+    ))]
+    UnknownSource { name: String },
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Describe how the fan speed is computed from the temperature.
+pub(crate) enum ControlMode {
+    /// Select a threshold with the NBFC hysteresis and use its speed.
+    #[default]
+    Threshold,
+    /// Interpolate linearly the speed between the thresholds.
+    Curve,
+    /// Adjust the speed with a PID controller to keep the temperature at a setpoint.
+    Pid,
+}
+impl fmt::Display for ControlMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+impl FromStr for ControlMode {
+    type Err = ControlModeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Threshold" => Ok(ControlMode::Threshold),
+            "Curve" => Ok(ControlMode::Curve),
+            "Pid" => Ok(ControlMode::Pid),
+            _ => Err(ControlModeParseError::UnknownMode { name: s.to_owned() }),
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ControlModeParseError {
+    #[snafu(display(
+        "`{}` is not a valid control mode (expected Threshold, Curve or Pid)",
+        name
+    ))]
+    UnknownMode { name: String },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Parameters of the PID controller, the output being a fan speed percentage.
+pub(crate) struct PidParameters {
+    /// Target temperature.
+    pub setpoint: f64,
+    /// Proportional gain (% per °C).
+    pub kp: f64,
+    /// Integral gain (% per °C per second).
+    pub ki: f64,
+    /// Derivative gain (% per °C/s).
+    pub kd: f64,
+    /// Lower limit of the integral term, to prevent windup.
+    pub integral_min: f64,
+    /// Upper limit of the integral term, to prevent windup.
+    pub integral_max: f64,
+    pub min_output: f64,
+    pub max_output: f64,
+}
+impl Default for PidParameters {
+    fn default() -> Self {
+        PidParameters {
+            setpoint: 75.0,
+            kp: 5.0,
+            ki: 0.1,
+            kd: 0.0,
+            integral_min: 0.0,
+            integral_max: 100.0,
+            min_output: 0.0,
+            max_output: 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Settings specific to a fan.
+pub(crate) struct FanSettings {
+    #[serde(default)]
+    pub temp_source: TempSource,
+    #[serde(default)]
+    pub control_mode: ControlMode,
+    /// Method used for the `Aggregate` temperature source, instead of the global one.
+    pub temp_compute: Option<TempComputeMethod>,
+    /// Maximum increase of the speed, in percent per second.
+    pub ramp_up: Option<f64>,
+    /// Maximum decrease of the speed, in percent per second.
+    pub ramp_down: Option<f64>,
+    // Tables have to be placed after the values to be serialized to TOML.
+    #[serde(default)]
+    pub pid: PidParameters,
+    /// Weights used for the `WeightedAverage` method, instead of the global ones.
+    pub temp_weights: Option<HashMap<String, f64>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+/// Stores the settings of the fans, keyed either by their name or by their index.
+pub(crate) struct FansSettings(HashMap<String, FanSettings>);
+
+impl FansSettings {
+    /// Get the settings for the fan at `index` named `name`.
+    /// The name takes precedence over the index.
+    pub fn get(&self, index: usize, name: &str) -> FanSettings {
+        self.0
+            .get(name)
+            .or_else(|| self.0.get(&index.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get a mutable reference to the settings for the fan at `index` named `name`,
+    /// inserting default settings under its name if there is none.
+    pub fn entry(&mut self, index: usize, name: &str) -> &mut FanSettings {
+        let key = if !self.0.contains_key(name) && self.0.contains_key(&index.to_string()) {
+            index.to_string()
+        } else {
+            name.to_owned()
+        };
+
+        self.0.entry(key).or_default()
+    }
+
+    /// Get the names of the sensors used by the fans, as temperature source or in their weights.
+    pub fn sensors(&self) -> impl Iterator<Item = &str> {
+        self.0.values().flat_map(|s| {
+            let source = match &s.temp_source {
+                TempSource::Sensor(name) => Some(name.as_str()),
+                _ => None,
+            };
+            let weights = s.temp_weights.iter().flat_map(|w| w.keys());
+            source.into_iter().chain(weights.map(String::as_str))
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+/// Describe a filter applied to a temperature before it is used to control the fans.
+pub(crate) enum TempFilter {
+    /// Exponential moving average, where `alpha` (between 0 and 1) is the weight of the new value.
+    Ema { alpha: f64 },
+    /// Median of the last `window` values.
+    Median { window: usize },
+    /// Maximum of the last `window` values.
+    Max { window: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+/// Describe a temperature sensor which is not provided by hwmon.
+pub(crate) enum CustomSensor {
+    /// Read the number in the file at `path`, multiplied by `scale`.
+    File { path: PathBuf, scale: Option<f64> },
+    /// Run `command` (the program followed by its arguments) and read the number on the first line
+    /// of its output, multiplied by `scale`.
+    /// The command is killed after `timeout` milliseconds.
+    Command {
+        command: Vec<String>,
+        timeout: Option<u64>,
+        scale: Option<f64>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+/// Describe a source used for the CPU temperature when there is no CPU sensor.
+pub(crate) enum CpuTempFallback {
+    /// ACPI thermal zone sensors on the hwmon interface (`acpitz`).
+    Acpi,
+    /// A thermal zone of `/sys/class/thermal`, or the hottest one if `zone` is not specified.
+    ThermalZone { zone: Option<u32> },
+    /// Every sensor of the hwmon chip `name`.
+    Chip { name: String },
+    /// The EC `register`, which holds the temperature in degrees Celsius.
+    EcRegister { register: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+/// Stores the ordered sources which are tried when there is no CPU sensor.
+pub(crate) struct CpuTempFallbacks(Vec<CpuTempFallback>);
+
+impl Default for CpuTempFallbacks {
+    fn default() -> Self {
+        CpuTempFallbacks(vec![
+            CpuTempFallback::Acpi,
+            CpuTempFallback::ThermalZone { zone: None },
+        ])
+    }
+}
+
+impl Deref for CpuTempFallbacks {
+    type Target = [CpuTempFallback];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Stores the correction applied to the readings of a sensor, e.g. for the offset `Tctl` of
+/// some `k10temp` chips.
+pub(crate) struct SensorCalibration {
+    /// Degrees Celsius added to the temperature, after the scale.
+    pub offset: Option<f64>,
+    /// Factor applied to the temperature.
+    pub scale: Option<f64>,
+}
+
+impl SensorCalibration {
+    /// Correct the temperature `temp`.
+    pub fn apply(&self, temp: f64) -> f64 {
+        temp * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Stores the checks used to reject the implausible readings of the sensors.
+pub(crate) struct Plausibility {
+    pub enabled: bool,
+    /// Lowest plausible temperature, in degrees Celsius.
+    pub min_temp: f64,
+    /// Highest plausible temperature, in degrees Celsius.
+    pub max_temp: f64,
+    /// Largest change (in degrees Celsius) between two readings,
+    /// larger changes are accepted only when they are confirmed by the next reading
+    /// (the last accepted temperature is used meanwhile).
+    pub max_jump: f64,
+    /// Number of identical consecutive readings after which a sensor is considered stuck
+    /// (`0` disables the check).
+    pub stuck_readings: u32,
+}
+
+impl Default for Plausibility {
+    fn default() -> Self {
+        Plausibility {
+            enabled: true,
+            min_temp: 0.0,
+            max_temp: 150.0,
+            max_jump: 30.0,
+            stuck_readings: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Describe what is applied when the computer switches to a power source.
+pub(crate) struct PowerSourceSettings {
+    /// Name of the profile to apply.
+    pub profile: Option<String>,
+    /// Name of the fan control configuration to use.
+    pub config: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Stores the settings applied automatically according to the power source.
+pub(crate) struct PowerPolicy {
+    pub ac: PowerSourceSettings,
+    pub battery: PowerSourceSettings,
+}
+
+impl PowerPolicy {
+    /// Get the settings for the power `source`.
+    pub fn get(&self, source: PowerSource) -> &PowerSourceSettings {
+        match source {
+            PowerSource::Ac => &self.ac,
+            PowerSource::Battery => &self.battery,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Stores how the profile follows the ACPI platform profile.
+pub(crate) struct PlatformProfileSettings {
+    /// Write the platform profile when the user changes the profile.
+    pub write: bool,
+    /// Profiles applied for each platform profile (e.g. `low-power`).
+    pub profiles: HashMap<String, String>,
+}
+
+impl PlatformProfileSettings {
+    /// Get the platform profile which is mapped to `profile`.
+    pub fn platform_profile_for(&self, profile: &str) -> Option<&str> {
+        self.profiles
+            .iter()
+            .filter(|(_, p)| *p == profile)
+            .map(|(platform_profile, _)| platform_profile.as_str())
+            .min()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Describe what the service does when a fan does not follow the requested speed.
+pub(crate) enum StallReaction {
+    /// Only log the failure.
+    #[default]
+    Log,
+    /// Set the other fans to their maximum speed.
+    RaiseOthers,
+    /// Enter the critical mode.
+    Critical,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Stores the settings used to detect the fans which do not follow the requested speed,
+/// by comparing the speed read from the EC with the one written.
+pub(crate) struct StallDetection {
+    pub enabled: bool,
+    /// Speed (in percent) below which a fan is considered stopped.
+    pub stall_speed: f64,
+    /// Time (in seconds) after which a stopped fan is considered stalled,
+    /// while a higher speed is requested.
+    pub stall_delay: u64,
+    /// Whether the fans which do not reach the requested speed are detected too.
+    /// Disabled by default as it misfires on the configurations whose read and write
+    /// scales differ.
+    pub check_target: bool,
+    /// Maximum difference (in percent) between the requested speed and the read one.
+    pub target_tolerance: f64,
+    /// Time (in seconds) after which a fan is considered unable to reach the requested speed.
+    pub target_delay: u64,
+    pub reaction: StallReaction,
+}
+
+impl Default for StallDetection {
+    fn default() -> Self {
+        StallDetection {
+            enabled: true,
+            stall_speed: 5.0,
+            stall_delay: 10,
+            check_target: false,
+            target_tolerance: 25.0,
+            target_delay: 60,
+            reaction: StallReaction::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// Describe how the fans are handled while the service cannot control them.
+pub(crate) enum FaultFallback {
+    /// Set the fans to their maximum speed.
+    #[default]
+    FullSpeed,
+    /// Give the control of the fans back to the firmware.
+    Firmware,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Stores how the service reacts to the errors of the sensors and of the EC.
+pub(crate) struct FaultPolicy {
+    /// Number of consecutive failed iterations tolerated before entering the degraded mode.
+    pub max_retries: u32,
+    /// Delay (in milliseconds) before the first retry, doubled after each failure.
+    pub retry_delay: u64,
+    /// Maximum delay (in milliseconds) between two retries.
+    pub max_retry_delay: u64,
+    pub fallback: FaultFallback,
+}
+
+impl Default for FaultPolicy {
+    fn default() -> Self {
+        FaultPolicy {
+            max_retries: 3,
+            retry_delay: 100,
+            max_retry_delay: 5000,
+            fallback: FaultFallback::default(),
+        }
+    }
+}
+
+impl FaultPolicy {
+    /// Get the delay to wait before retrying after `failures` consecutive failures.
+    pub fn retry_delay(&self, failures: u32) -> Duration {
+        let factor = 1u64 << failures.saturating_sub(1).min(16);
+        Duration::from_millis(
+            self.retry_delay
+                .saturating_mul(factor)
+                .min(self.max_retry_delay),
+        )
+    }
+}
+
 // ANCHOR: ServiceConfig
 #[derive(Debug, Serialize, Deserialize, Default)]
 /// Stores the service configuration which can be written to the disk.
@@ -101,6 +702,56 @@ pub(crate) struct ServiceConfig {
     pub temp_compute: TempComputeMethod,
     #[serde(default)]
     pub check_control_config: bool,
+    /// Name of the profile applied on top of the fan control configuration.
+    #[serde(default)]
+    pub profile: String,
+    /// Read back the values written to the EC to detect when the firmware overwrites them.
+    #[serde(default)]
+    pub verify_writes: bool,
+    /// Sources tried in order for the CPU temperature when there is no CPU sensor.
+    // Kept before the tables, as an empty array is written as a value.
+    #[serde(default)]
+    pub cpu_temp_fallback: CpuTempFallbacks,
+    #[serde(default)]
+    pub backend: FanBackendSettings,
+    /// Devices used to access to the EC.
+    #[serde(default)]
+    pub ec_devices: ECDevices,
+    /// Other ECs which control some of the fans.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secondary_ecs: Vec<SecondaryEC>,
+    /// Methods called with the `AcpiCall` access mode.
+    #[serde(default)]
+    pub acpi_call: AcpiCallSettings,
+    #[serde(default)]
+    pub power: PowerPolicy,
+    #[serde(default)]
+    pub platform_profile: PlatformProfileSettings,
+    #[serde(default)]
+    pub stall_detection: StallDetection,
+    #[serde(default)]
+    pub fault_policy: FaultPolicy,
+    #[serde(default)]
+    pub plausibility: Plausibility,
+    /// Weights of the temperature sources (`CPU`, `GPU`, `NVME`, `ACPI` or `chip/label`)
+    /// for the `WeightedAverage` method.
+    #[serde(default)]
+    pub temp_weights: HashMap<String, f64>,
+    /// Corrections applied to the sensors (`chip/label` or `custom/name`).
+    #[serde(default)]
+    pub calibration: HashMap<String, SensorCalibration>,
+    /// Sensors which are not provided by hwmon, identified by `custom/name`.
+    #[serde(default)]
+    pub custom_sensors: HashMap<String, CustomSensor>,
+    /// Filters applied to the temperature sources (`CPU`, `GPU`, `NVME`, `ACPI` or `chip/label`).
+    #[serde(default)]
+    pub temp_filters: HashMap<String, TempFilter>,
+    #[serde(default)]
+    pub fans: FansSettings,
+    /// Time ranges during which a profile or a speed cap is applied.
+    // An empty array would be written as a value after the tables.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ScheduleEntry>,
 }
 // ANCHOR_END: ServiceConfig
 
@@ -113,6 +764,24 @@ impl From<NbfcServiceSettings> for ServiceConfig {
             target_fans_speeds: s.target_fan_speeds.iter().map(|s| *s as f64).collect(),
             temp_compute: TempComputeMethod::default(),
             check_control_config: false,
+            profile: String::new(),
+            verify_writes: false,
+            backend: FanBackendSettings::default(),
+            ec_devices: ECDevices::default(),
+            secondary_ecs: Vec::new(),
+            acpi_call: AcpiCallSettings::default(),
+            power: PowerPolicy::default(),
+            platform_profile: PlatformProfileSettings::default(),
+            stall_detection: StallDetection::default(),
+            fault_policy: FaultPolicy::default(),
+            plausibility: Plausibility::default(),
+            cpu_temp_fallback: CpuTempFallbacks::default(),
+            temp_weights: HashMap::new(),
+            calibration: HashMap::new(),
+            custom_sensors: HashMap::new(),
+            temp_filters: HashMap::new(),
+            fans: FansSettings::default(),
+            schedule: Vec::new(),
         }
     }
 }
@@ -130,6 +799,12 @@ pub(crate) enum ServiceConfigSaveError {
         source
     ))]
     SaveConfig { source: std::io::Error },
+
+    #[snafu(display(
+        "An error occured while trying to serialize the service configuration: {}",
+        source
+    ))]
+    Serialization { source: toml::ser::Error },
 }
 
 #[derive(Debug, Snafu)]
@@ -160,6 +835,24 @@ pub(crate) enum ServiceConfigLoadError {
 
     #[snafu(display("There is no configuration available"))]
     NoConfig {},
+
+    #[snafu(display(
+        "The watchdog of the Thinkpad backend must be between 1 and 120 seconds, not {}",
+        watchdog
+    ))]
+    InvalidWatchdog { watchdog: u32 },
+
+    #[snafu(display("The thresholds of the Thinkpad backend cannot be empty"))]
+    EmptyThinkpadThresholds {},
+
+    #[snafu(display("Secondary ECs cannot be used with the `AcpiCall` access mode"))]
+    AcpiCallSecondaryECs {},
+
+    #[snafu(display(
+        "The secondary EC {} has the devices of another EC, its own ones must be given",
+        index
+    ))]
+    SecondaryECDevices { index: usize },
 }
 
 impl ServiceConfig {
@@ -173,7 +866,9 @@ impl ServiceConfig {
                 .read_to_string(&mut buf)
                 .context(LoadService {})?;
 
-            toml::from_str::<ServiceConfig>(&buf).context(TomlDeserialize {})
+            let config = toml::from_str::<ServiceConfig>(&buf).context(TomlDeserialize {})?;
+            config.check()?;
+            Ok(config)
         } else if NBFC_SETTINGS_PATH.is_file() {
             File::open(*NBFC_SETTINGS_PATH)
                 .context(OpenNbfcServiceConfig {})?
@@ -188,11 +883,409 @@ impl ServiceConfig {
         }
     }
 
+    /// Check the values which are not restricted by their types.
+    fn check(&self) -> Result<(), ServiceConfigLoadError> {
+        if let FanBackendSettings::Thinkpad {
+            watchdog,
+            temperature_thresholds,
+            ..
+        } = &self.backend
+        {
+            // The limits of `thinkpad_acpi`, 0 would disable the watchdog.
+            if let Some(watchdog) = *watchdog {
+                if !(1..=120).contains(&watchdog) {
+                    return InvalidWatchdog { watchdog }.fail();
+                }
+            }
+            // The speed of the fan is computed from the thresholds.
+            if temperature_thresholds.as_ref().is_some_and(Vec::is_empty) {
+                return EmptyThinkpadThresholds {}.fail();
+            }
+        }
+        // The ACPI methods control every fan.
+        if self.ec_access_mode == ECAccessMode::AcpiCall && !self.secondary_ecs.is_empty() {
+            return AcpiCallSecondaryECs {}.fail();
+        }
+        // The default devices are the ones of the main EC.
+        let ecs = std::iter::once((self.ec_access_mode, &self.ec_devices)).chain(
+            self.secondary_ecs
+                .iter()
+                .map(|ec| (ec.access_mode, &ec.devices)),
+        );
+        for (index, ec) in self.secondary_ecs.iter().enumerate() {
+            if ec.devices == ECDevices::default()
+                || ecs
+                    .clone()
+                    .take(index + 1)
+                    .any(|(mode, devices)| ec.devices.shares_ec(ec.access_mode, devices, mode))
+            {
+                return SecondaryECDevices { index }.fail();
+            }
+        }
+
+        Ok(())
+    }
+
     /// Save the `ServiceConfig` to the disk.
     pub(crate) fn save(&self) -> Result<(), ServiceConfigSaveError> {
+        let serialized = toml::to_string_pretty(self).context(Serialization {})?;
         File::create(&*CONFIG_FILE_PATH)
             .context(CreateConfig {})?
-            .write_all(toml::to_string_pretty(self).unwrap().as_bytes())
+            .write_all(serialized.as_bytes())
             .context(SaveConfig {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{TimeOfDay, Weekday};
+
+    #[test]
+    fn platform_profile_for() {
+        let settings = PlatformProfileSettings {
+            write: true,
+            profiles: vec![
+                ("low-power", "silent"),
+                ("quiet", "silent"),
+                ("performance", "performance"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        };
+
+        assert_eq!(settings.platform_profile_for("silent"), Some("low-power"));
+        assert_eq!(
+            settings.platform_profile_for("performance"),
+            Some("performance")
+        );
+        assert_eq!(settings.platform_profile_for("balanced"), None);
+    }
+
+    /// Write `config` with `toml` and read it back.
+    fn round_trip(config: &ServiceConfig) -> ServiceConfig {
+        let serialized = toml::to_string_pretty(config).unwrap();
+        toml::from_str(&serialized).unwrap()
+    }
+
+    #[test]
+    fn serialize_fans() {
+        let mut config = ServiceConfig::default();
+        config.fans.entry(0, "CPU fan").ramp_up = Some(5.0);
+        config.fans.entry(1, "GPU fan").control_mode = ControlMode::Pid;
+        config.fans.entry(2, "Chassis fan").temp_compute = Some(TempComputeMethod::Max);
+        config.fans.entry(2, "Chassis fan").temp_weights =
+            Some(vec![("NVME".to_string(), 2.0)].into_iter().collect());
+        config.temp_compute = TempComputeMethod::WeightedAverage;
+        config.temp_weights.insert("CPU".to_string(), 1.0);
+
+        let deserialized = round_trip(&config);
+        assert_eq!(deserialized.fans, config.fans);
+        assert_eq!(deserialized.temp_compute, config.temp_compute);
+        assert_eq!(deserialized.temp_weights, config.temp_weights);
+    }
+
+    #[test]
+    fn serialize_sensors() {
+        let mut config = ServiceConfig::default();
+        config.custom_sensors.insert(
+            "nvidia".to_string(),
+            CustomSensor::Command {
+                command: vec!["nvidia-smi".to_string(), "-q".to_string()],
+                timeout: Some(500),
+                scale: None,
+            },
+        );
+        config.custom_sensors.insert(
+            "bmc".to_string(),
+            CustomSensor::File {
+                path: "/run/bmc/temp".into(),
+                scale: Some(0.001),
+            },
+        );
+        config.calibration.insert(
+            "k10temp/Tctl".to_string(),
+            SensorCalibration {
+                offset: Some(-27.0),
+                scale: None,
+            },
+        );
+        config.cpu_temp_fallback = CpuTempFallbacks(vec![
+            CpuTempFallback::Chip {
+                name: "it8728".to_string(),
+            },
+            CpuTempFallback::ThermalZone { zone: Some(1) },
+            CpuTempFallback::EcRegister { register: 0x58 },
+        ]);
+        config
+            .temp_filters
+            .insert("CPU".into(), TempFilter::Ema { alpha: 0.5 });
+        config
+            .temp_filters
+            .insert("acpitz/temp1".into(), TempFilter::Median { window: 5 });
+        config.plausibility.stuck_readings = 10;
+
+        let deserialized = round_trip(&config);
+        assert_eq!(deserialized.custom_sensors, config.custom_sensors);
+        assert_eq!(deserialized.calibration, config.calibration);
+        assert_eq!(deserialized.cpu_temp_fallback, config.cpu_temp_fallback);
+        assert_eq!(deserialized.temp_filters, config.temp_filters);
+        assert_eq!(deserialized.plausibility, config.plausibility);
+    }
+
+    #[test]
+    fn serialize_profiles() {
+        let mut config = ServiceConfig::default();
+        config.power.battery.profile = Some("silent".to_string());
+        config.platform_profile.write = true;
+        config
+            .platform_profile
+            .profiles
+            .insert("low-power".to_string(), "silent".to_string());
+        config.schedule.push(ScheduleEntry {
+            name: "Office".to_string(),
+            days: vec![Weekday::Mon, Weekday::Fri],
+            start: TimeOfDay::new(9, 0),
+            end: TimeOfDay::new(18, 30),
+            profile: None,
+            max_speed: Some(40.0),
+        });
+
+        let deserialized = round_trip(&config);
+        assert_eq!(deserialized.power, config.power);
+        assert_eq!(deserialized.platform_profile, config.platform_profile);
+        assert_eq!(deserialized.schedule, config.schedule);
+    }
+
+    #[test]
+    fn serialize_faults() {
+        let mut config = ServiceConfig::default();
+        config.stall_detection.reaction = StallReaction::RaiseOthers;
+        config.fault_policy.fallback = FaultFallback::Firmware;
+
+        let deserialized = round_trip(&config);
+        assert_eq!(deserialized.stall_detection, config.stall_detection);
+        assert_eq!(deserialized.fault_policy, config.fault_policy);
+    }
+
+    #[test]
+    fn serialize_ec_access() {
+        let mut config = ServiceConfig {
+            backend: FanBackendSettings::Hwmon {
+                chip: "thinkpad".to_string(),
+                pwms: vec![1],
+                max_rpm: None,
+            },
+            ..Default::default()
+        };
+        config.ec_devices.ec_sys = "/sys/kernel/debug/ec/ec1/io".into();
+        config.ec_devices.port_sleep = 50;
+        config.secondary_ecs.push(SecondaryEC {
+            access_mode: ECAccessMode::RawPort,
+            fans: vec![1],
+            devices: ECDevices {
+                command_port: 0x6c,
+                data_port: 0x68,
+                ..Default::default()
+            },
+        });
+        config.acpi_call.init = Some(r"\_SB.PCI0.LPCB.EC0.FMOD 0x1".to_string());
+        config.acpi_call.fans.push(AcpiCallFan {
+            write: r"\_SB.PCI0.LPCB.EC0.SFAN 0x0".to_string(),
+            read: None,
+        });
+
+        let deserialized = round_trip(&config);
+        assert_eq!(deserialized.backend, config.backend);
+        assert_eq!(deserialized.ec_devices, config.ec_devices);
+        assert_eq!(deserialized.secondary_ecs, config.secondary_ecs);
+        assert_eq!(deserialized.acpi_call, config.acpi_call);
+    }
+
+    #[test]
+    fn serialize_default() {
+        let deserialized = round_trip(&ServiceConfig::default());
+        assert!(deserialized.secondary_ecs.is_empty());
+        assert!(deserialized.schedule.is_empty());
+        assert_eq!(deserialized.cpu_temp_fallback, CpuTempFallbacks::default());
+    }
+
+    #[test]
+    fn serialize_empty_collections() {
+        // The arrays written as values must not follow the tables.
+        let mut config = ServiceConfig {
+            target_fans_speeds: Vec::new(),
+            backend: FanBackendSettings::Hwmon {
+                chip: "thinkpad".to_string(),
+                pwms: Vec::new(),
+                max_rpm: None,
+            },
+            secondary_ecs: Vec::new(),
+            cpu_temp_fallback: CpuTempFallbacks(Vec::new()),
+            temp_weights: HashMap::new(),
+            calibration: HashMap::new(),
+            custom_sensors: HashMap::new(),
+            temp_filters: HashMap::new(),
+            fans: FansSettings::default(),
+            schedule: Vec::new(),
+            ..Default::default()
+        };
+        config.fans.entry(0, "CPU fan").temp_weights = Some(HashMap::new());
+        config.acpi_call.fans.clear();
+        config.platform_profile.profiles.clear();
+
+        let deserialized = round_trip(&config);
+        assert!(deserialized.target_fans_speeds.is_empty());
+        assert_eq!(deserialized.backend, config.backend);
+        assert!(deserialized.secondary_ecs.is_empty());
+        // An empty list disables the fallbacks and must not be replaced by the default ones.
+        assert!(deserialized.cpu_temp_fallback.is_empty());
+        assert!(deserialized.temp_weights.is_empty());
+        assert!(deserialized.calibration.is_empty());
+        assert!(deserialized.custom_sensors.is_empty());
+        assert!(deserialized.temp_filters.is_empty());
+        assert_eq!(deserialized.fans, config.fans);
+        assert!(deserialized.schedule.is_empty());
+        assert_eq!(deserialized.acpi_call, config.acpi_call);
+        assert_eq!(deserialized.platform_profile, config.platform_profile);
+    }
+
+    #[test]
+    fn thinkpad_backend() {
+        let config: ServiceConfig = toml::from_str(
+            r#"
+            ec_access_mode = "Either"
+            selected_fan_config = ""
+            auto = true
+            target_fans_speeds = []
+
+            [backend]
+            type = "Thinkpad"
+            watchdog = 30
+
+            [[backend.temperature_thresholds]]
+            UpThreshold = 0
+            DownThreshold = 0
+            FanSpeed = 20.0
+
+            [[backend.temperature_thresholds]]
+            UpThreshold = 70
+            DownThreshold = 60
+            FanSpeed = 100.0
+            "#,
+        )
+        .unwrap();
+        assert!(config.check().is_ok());
+        match &config.backend {
+            FanBackendSettings::Thinkpad {
+                temperature_thresholds: Some(thresholds),
+                ..
+            } => assert_eq!(thresholds.len(), 2),
+            backend => panic!("Unexpected backend: {:?}", backend),
+        }
+        let serialized = toml::to_string_pretty(&config).unwrap();
+        let deserialized: ServiceConfig = toml::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.backend, config.backend);
+
+        for watchdog in [0, 121] {
+            let config = ServiceConfig {
+                backend: FanBackendSettings::Thinkpad {
+                    watchdog: Some(watchdog),
+                    max_rpm: None,
+                    temperature_thresholds: None,
+                },
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.check(),
+                Err(ServiceConfigLoadError::InvalidWatchdog { .. })
+            ));
+        }
+
+        let config = ServiceConfig {
+            backend: FanBackendSettings::Thinkpad {
+                watchdog: None,
+                max_rpm: None,
+                temperature_thresholds: Some(Vec::new()),
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.check(),
+            Err(ServiceConfigLoadError::EmptyThinkpadThresholds {})
+        ));
+    }
+
+    #[test]
+    fn acpi_call_secondary_ecs() {
+        let mut config = ServiceConfig {
+            ec_access_mode: ECAccessMode::AcpiCall,
+            ..Default::default()
+        };
+        assert!(config.check().is_ok());
+        config.secondary_ecs.push(SecondaryEC {
+            access_mode: ECAccessMode::RawPort,
+            fans: vec![1],
+            devices: ECDevices {
+                command_port: 0x6c,
+                data_port: 0x68,
+                ..Default::default()
+            },
+        });
+        assert!(matches!(
+            config.check(),
+            Err(ServiceConfigLoadError::AcpiCallSecondaryECs {})
+        ));
+    }
+
+    #[test]
+    fn secondary_ec_devices() {
+        let mut config = ServiceConfig::default();
+        config.secondary_ecs.push(SecondaryEC {
+            access_mode: ECAccessMode::RawPort,
+            fans: vec![1],
+            devices: ECDevices {
+                command_port: 0x6c,
+                data_port: 0x68,
+                ..Default::default()
+            },
+        });
+        assert!(config.check().is_ok());
+
+        // The devices of the main EC.
+        config.secondary_ecs.push(SecondaryEC {
+            access_mode: ECAccessMode::ECSys,
+            fans: vec![2],
+            devices: ECDevices::default(),
+        });
+        assert!(matches!(
+            config.check(),
+            Err(ServiceConfigLoadError::SecondaryECDevices { index: 1 })
+        ));
+        config.secondary_ecs[1].devices.port_timeout = 200;
+        assert!(matches!(
+            config.check(),
+            Err(ServiceConfigLoadError::SecondaryECDevices { index: 1 })
+        ));
+        config.secondary_ecs[1].devices.ec_sys = "/sys/kernel/debug/ec/ec1/io".into();
+        assert!(config.check().is_ok());
+
+        // The devices of another secondary EC.
+        config.secondary_ecs.push(config.secondary_ecs[0].clone());
+        assert!(matches!(
+            config.check(),
+            Err(ServiceConfigLoadError::SecondaryECDevices { index: 2 })
+        ));
+    }
+
+    #[test]
+    fn retry_delay() {
+        let policy = FaultPolicy::default();
+        assert_eq!(policy.retry_delay(1), Duration::from_millis(100));
+        assert_eq!(policy.retry_delay(2), Duration::from_millis(200));
+        assert_eq!(policy.retry_delay(4), Duration::from_millis(800));
+        assert_eq!(policy.retry_delay(10), Duration::from_millis(5000));
+        assert_eq!(policy.retry_delay(u32::MAX), Duration::from_millis(5000));
+    }
+}
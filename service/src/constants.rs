@@ -9,3 +9,10 @@ pub const OBJ_PATH_STR: &str = "/com/musikid/fancy";
 pub const BUS_NAME_STR: &str = "com.musikid.fancy";
 pub static ROOT_CONFIG_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/etc/fancy"));
 pub static CONTROL_CONFIGS_DIR_PATH: Lazy<PathBuf> = Lazy::new(|| ROOT_CONFIG_PATH.join("configs"));
+pub static ACPI_FIRMWARE_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/sys/firmware/acpi"));
+pub static POWER_SUPPLY_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/sys/class/power_supply"));
+pub static HWMON_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/sys/class/hwmon"));
+pub static ACPI_CALL_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/proc/acpi/call"));
+pub static THINKPAD_FAN_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/proc/acpi/ibm/fan"));
+pub static THERMAL_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/sys/class/thermal"));
+pub static PROFILES_DIR_PATH: Lazy<PathBuf> = Lazy::new(|| ROOT_CONFIG_PATH.join("profiles"));
@@ -0,0 +1,221 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::debug;
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::config::service::CustomSensor;
+
+/// Name of the chip of the custom sensors, which are identified by `custom/name`.
+pub(crate) const CUSTOM_CHIP: &str = "custom";
+
+const DEFAULT_COMMAND_TIMEOUT: u64 = 1000;
+
+/// Parse the number on the first line of `output`.
+fn parse_value(output: &str) -> Option<f64> {
+    output.lines().next()?.trim().parse().ok()
+}
+
+#[derive(Debug, Default)]
+/// Holds the command which is running for a sensor and the last value read.
+struct SensorState {
+    child: Option<(Child, Instant)>,
+    value: Option<f64>,
+}
+
+impl SensorState {
+    /// Collect the output of the command if it has exited, or kill it after `timeout`,
+    /// then start it again if it is not running.
+    /// The commands run between two polls, so the value read is the one of the previous run.
+    fn poll_command(&mut self, command: &[String], timeout: Duration) -> Option<f64> {
+        if let Some((child, started)) = &mut self.child {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    let mut output = String::new();
+                    if let Some(mut stdout) = child.stdout.take() {
+                        if let Err(e) = stdout.read_to_string(&mut output) {
+                            debug!("Could not read the output of {:?}: {}", command, e);
+                        }
+                    }
+                    self.value = parse_value(&output).filter(|_| status.success());
+                    if self.value.is_none() {
+                        debug!(
+                            "Could not get a temperature from {:?} ({}): {:?}",
+                            command, status, output
+                        );
+                    }
+                    self.child = None;
+                }
+                Ok(None) if started.elapsed() >= timeout => {
+                    debug!("{:?} has timed out", command);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    self.child = None;
+                    self.value = None;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Could not wait for {:?}: {}", command, e);
+                    self.child = None;
+                    self.value = None;
+                }
+            }
+        }
+
+        if self.child.is_none() {
+            let (program, args) = command.split_first()?;
+            match Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(child) => self.child = Some((child, Instant::now())),
+                Err(e) => {
+                    debug!("Could not run {:?}: {}", command, e);
+                    self.value = None;
+                }
+            }
+        }
+
+        self.value
+    }
+}
+
+impl Drop for SensorState {
+    fn drop(&mut self) {
+        if let Some((child, _)) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Reads the custom sensors and runs their commands in the background.
+pub(crate) struct CustomSensors {
+    states: HashMap<String, SensorState>,
+}
+
+impl CustomSensors {
+    /// Read the custom `sensors`, keyed by name.
+    /// Returns the available temperatures, identified by `custom/name`.
+    pub fn read(&mut self, sensors: &HashMap<String, CustomSensor>) -> HashMap<String, f64> {
+        self.states.retain(|name, _| sensors.contains_key(name));
+
+        sensors
+            .iter()
+            .filter_map(|(name, sensor)| {
+                let value = match sensor {
+                    CustomSensor::File { path, scale } => match read_to_string(path) {
+                        Ok(content) => parse_value(&content).map(|v| v * scale.unwrap_or(1.0)),
+                        Err(e) => {
+                            debug!("Could not read `{}`: {}", path.display(), e);
+                            None
+                        }
+                    },
+                    CustomSensor::Command {
+                        command,
+                        timeout,
+                        scale,
+                    } => {
+                        let timeout =
+                            Duration::from_millis(timeout.unwrap_or(DEFAULT_COMMAND_TIMEOUT));
+                        self.states
+                            .entry(name.to_owned())
+                            .or_default()
+                            .poll_command(command, timeout)
+                            .map(|v| v * scale.unwrap_or(1.0))
+                    }
+                };
+
+                value
+                    .filter(|v| v.is_finite())
+                    .map(|v| (format!("{}/{}", CUSTOM_CHIP, name), v))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn sensors(name: &str, sensor: CustomSensor) -> HashMap<String, CustomSensor> {
+        vec![(name.to_owned(), sensor)].into_iter().collect()
+    }
+
+    fn command(script: &str, timeout: u64) -> CustomSensor {
+        CustomSensor::Command {
+            command: vec!["sh".to_owned(), "-c".to_owned(), script.to_owned()],
+            timeout: Some(timeout),
+            scale: None,
+        }
+    }
+
+    #[test]
+    fn file() {
+        let mut custom = CustomSensors::default();
+        let s = sensors(
+            "bmc",
+            CustomSensor::File {
+                path: "tests/custom_sensors/bmc_temp".into(),
+                scale: Some(0.001),
+            },
+        );
+        assert_eq!(custom.read(&s).get("custom/bmc"), Some(&45.5));
+
+        let s = sensors(
+            "bmc",
+            CustomSensor::File {
+                path: "tests/custom_sensors/inexistent".into(),
+                scale: None,
+            },
+        );
+        assert!(custom.read(&s).is_empty());
+    }
+
+    #[test]
+    fn command_output() {
+        let mut custom = CustomSensors::default();
+        let s = sensors("gpu", command("echo 52; echo 10", 5000));
+
+        // The command is started at the first read.
+        assert!(custom.read(&s).is_empty());
+        let mut value = None;
+        for _ in 0..100 {
+            sleep(Duration::from_millis(20));
+            value = custom.read(&s).get("custom/gpu").copied();
+            if value.is_some() {
+                break;
+            }
+        }
+        assert_eq!(value, Some(52.0));
+
+        let s = sensors("gpu", command("echo invalid", 5000));
+        let mut custom = CustomSensors::default();
+        for _ in 0..10 {
+            sleep(Duration::from_millis(20));
+            assert!(custom.read(&s).is_empty());
+        }
+    }
+
+    #[test]
+    fn command_timeout() {
+        let mut custom = CustomSensors::default();
+        let s = sensors("slow", command("sleep 5; echo 40", 50));
+
+        assert!(custom.read(&s).is_empty());
+        sleep(Duration::from_millis(100));
+        assert!(custom.read(&s).is_empty());
+        // The command has been started again after being killed.
+        assert!(custom.states["slow"].child.is_some());
+    }
+}
@@ -0,0 +1,269 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::{debug, info};
+
+use std::fs::{read_to_string, write};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use super::backend::FanBackend;
+use crate::config::service::AcpiCallSettings;
+use crate::nbfc::FanControlConfigV2;
+
+type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Parse the result of a successful call, as returned by `acpi_call`.
+/// Only the integers are supported, since the fan speeds are numbers.
+fn parse_result(result: &str) -> Result<u64> {
+    let result = result.trim_end_matches('\0').trim();
+    result
+        .strip_prefix("0x")
+        .and_then(|v| u64::from_str_radix(v, 16).ok())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("`{}` is not an integer", result),
+            )
+        })
+}
+
+#[derive(Debug)]
+/// Holds the range of the speed values of a fan and its state.
+struct CallFan {
+    min_value: f64,
+    max_value: f64,
+    min_value_read: f64,
+    max_value_read: f64,
+    /// Last value written.
+    written_value: Option<u64>,
+}
+
+#[derive(Debug)]
+/// Controls the fans by calling ACPI methods through the module `acpi_call` (`/proc/acpi/call`),
+/// for the laptops which do not expose the fan speeds in registers.
+/// The speeds are mapped to the values of the fan control configuration.
+pub(crate) struct AcpiCallBackend {
+    path: PathBuf,
+    settings: AcpiCallSettings,
+    fans: Vec<CallFan>,
+}
+
+impl AcpiCallBackend {
+    /// Create a backend for the call file at `path` (usually `/proc/acpi/call`).
+    pub fn new<P: Into<PathBuf>>(path: P, settings: &AcpiCallSettings) -> Self {
+        AcpiCallBackend {
+            path: path.into(),
+            settings: settings.clone(),
+            fans: Vec::new(),
+        }
+    }
+
+    /// Call `method` (with its arguments) and get the result.
+    fn call(&self, method: &str) -> Result<String> {
+        debug!("Calling `{}` with `{}`", method, self.path.display());
+        write(&self.path, method)?;
+        let result = read_to_string(&self.path)?;
+        if let Some(error) = result.trim().strip_prefix("Error: ") {
+            return Err(Error::other(format!(
+                "`{}` failed: {}",
+                method,
+                error.trim_end_matches('\0')
+            )));
+        }
+        Ok(result)
+    }
+
+    fn fan(&self, fan_index: usize) -> Result<&CallFan> {
+        self.fans.get(fan_index).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("There are no methods for the fan with index {}", fan_index),
+            )
+        })
+    }
+
+    /// Read the speed value of the fan at `fan_index`, if it has a read method.
+    fn read_value(&self, fan_index: usize) -> Result<Option<u64>> {
+        match &self.settings.fans[fan_index].read {
+            Some(read) => self.call(read).and_then(|r| parse_result(&r)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FanBackend for AcpiCallBackend {
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result {
+        if self.settings.fans.len() < c.fan_configurations.len() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{} fans are configured but the methods of only {} are given",
+                    c.fan_configurations.len(),
+                    self.settings.fans.len()
+                ),
+            ));
+        }
+
+        self.fans = c
+            .fan_configurations
+            .iter()
+            .map(|f| {
+                let (min_read, max_read) = if f.independent_read_min_max_values {
+                    (f.min_speed_value_read, f.max_speed_value_read)
+                } else {
+                    (f.min_speed_value, f.max_speed_value)
+                };
+                CallFan {
+                    min_value: f64::from(f.min_speed_value),
+                    max_value: f64::from(f.max_speed_value),
+                    min_value_read: f64::from(min_read),
+                    max_value_read: f64::from(max_read),
+                    written_value: None,
+                }
+            })
+            .collect();
+        info!(
+            "Controlling {} fans with ACPI calls through `{}`",
+            self.fans.len(),
+            self.path.display()
+        );
+
+        self.init()
+    }
+
+    fn init(&mut self) -> Result {
+        match &self.settings.init {
+            Some(init) => self.call(init).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn reset(&mut self, _reset_all: bool) -> Result {
+        for fan in &mut self.fans {
+            fan.written_value = None;
+        }
+        match &self.settings.reset {
+            Some(reset) => self.call(reset).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result {
+        let fan = self.fan(fan_index)?;
+        let value = (fan.min_value
+            + (fan.max_value - fan.min_value) * speed_percent.clamp(0.0, 100.0) / 100.0)
+            .round() as u64;
+
+        self.call(&format!(
+            "{} {:#x}",
+            self.settings.fans[fan_index].write, value
+        ))?;
+        self.fans[fan_index].written_value = Some(value);
+        Ok(())
+    }
+
+    fn read_speed_percent(&self, fan_index: usize) -> Result<f64> {
+        let fan = self.fan(fan_index)?;
+        let (value, min, max) = match self.read_value(fan_index)? {
+            Some(value) => (value, fan.min_value_read, fan.max_value_read),
+            // The last written speed is used when the speed cannot be read.
+            None => match fan.written_value {
+                Some(value) => (value, fan.min_value, fan.max_value),
+                None => return Ok(0.0),
+            },
+        };
+        if min == max {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The speed values of the fan with index {} have an empty range",
+                    fan_index
+                ),
+            ));
+        }
+
+        let percent = (value as f64 - min) / (max - min) * 100.0;
+        Ok(percent.clamp(0.0, 100.0))
+    }
+
+    fn verify_init(&self) -> Result<bool> {
+        // The methods do not tell whether the firmware took the control back.
+        Ok(true)
+    }
+
+    fn verify_speed(&self, fan_index: usize) -> Result<bool> {
+        let fan = self.fan(fan_index)?;
+        match fan.written_value {
+            Some(written_value) => Ok(self
+                .read_value(fan_index)?
+                .is_none_or(|v| v == written_value)),
+            None => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::service::AcpiCallFan;
+    use crate::ec_control::backend::fans_config as config;
+    use std::fs::remove_file;
+
+    fn settings() -> AcpiCallSettings {
+        AcpiCallSettings {
+            init: Some(r"\_SB.PCI0.LPCB.EC0.FMOD 0x1".to_owned()),
+            reset: Some(r"\_SB.PCI0.LPCB.EC0.FMOD 0x0".to_owned()),
+            fans: vec![
+                AcpiCallFan {
+                    write: r"\_SB.PCI0.LPCB.EC0.SFAN 0x0".to_owned(),
+                    read: Some(r"\_SB.PCI0.LPCB.EC0.GFAN 0x0".to_owned()),
+                },
+                AcpiCallFan {
+                    write: r"\_SB.PCI0.LPCB.EC0.SFAN 0x1".to_owned(),
+                    read: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn results() {
+        assert_eq!(parse_result("0x2a\0").unwrap(), 42);
+        assert_eq!(parse_result("0x0").unwrap(), 0);
+        assert!(parse_result("not called").is_err());
+        assert!(parse_result("{0x01, 0x02}").is_err());
+    }
+
+    #[test]
+    fn calls() {
+        let path = std::env::temp_dir().join(format!("fancy-acpi-call-{}", std::process::id()));
+        let read = || read_to_string(&path).unwrap();
+
+        let mut backend = AcpiCallBackend::new(&path, &settings());
+        assert!(backend.refresh_config(&config(3)).is_err());
+        backend.refresh_config(&config(2)).unwrap();
+        assert_eq!(read(), r"\_SB.PCI0.LPCB.EC0.FMOD 0x1");
+
+        // The file holds the last call, like `acpi_call` before it is evaluated.
+        backend.write_speed_percent(0, 50.0).unwrap();
+        assert_eq!(read(), r"\_SB.PCI0.LPCB.EC0.SFAN 0x0 0x80");
+        backend.write_speed_percent(1, 150.0).unwrap();
+        assert_eq!(read(), r"\_SB.PCI0.LPCB.EC0.SFAN 0x1 0xff");
+        assert!(backend.write_speed_percent(2, 50.0).is_err());
+
+        // Without a read method, the last written speed is used.
+        assert_eq!(backend.read_speed_percent(1).unwrap(), 100.0);
+        assert!(backend.verify_speed(1).unwrap());
+        // The result of the fake file is the call itself, which is not an integer.
+        assert!(backend.read_speed_percent(0).is_err());
+        assert!(backend.verify_init().unwrap());
+        assert!(backend.read_register(0x58).is_err());
+
+        backend.reset(true).unwrap();
+        assert_eq!(read(), r"\_SB.PCI0.LPCB.EC0.FMOD 0x0");
+        assert_eq!(backend.read_speed_percent(1).unwrap(), 0.0);
+
+        remove_file(&path).unwrap();
+    }
+}
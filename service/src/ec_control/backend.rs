@@ -0,0 +1,365 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+
+use super::read::ECReader;
+use super::write::ECWriter;
+use super::RW;
+use crate::nbfc::FanControlConfigV2;
+
+type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Describe a way to control the fans, e.g. through the EC or through a kernel driver.
+/// The fans are identified by their index in the fan control configuration.
+pub(crate) trait FanBackend: Debug {
+    /// Refresh the configuration of the fans and take the control of them.
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result;
+
+    /// Take the control of the fans, e.g. after it has been given back to the firmware.
+    fn init(&mut self) -> Result;
+
+    /// Give the control of the fans back to the firmware.
+    /// Resets everything which has been written (even when it's not required) if `reset_all` is true.
+    fn reset(&mut self, reset_all: bool) -> Result;
+
+    /// Write the speed percent of the fan at `fan_index`.
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result;
+
+    /// Read the speed percent of the fan at `fan_index`.
+    fn read_speed_percent(&self, fan_index: usize) -> Result<f64>;
+
+    /// Check that the control of the fans has not been taken back by the firmware.
+    fn verify_init(&self) -> Result<bool>;
+
+    /// Check that the speed written for the fan at `fan_index` has not been overwritten.
+    fn verify_speed(&self, fan_index: usize) -> Result<bool>;
+
+    /// Get the fan control configuration provided by the backend, used when none is selected.
+    fn default_config(&self) -> Option<FanControlConfigV2> {
+        None
+    }
+
+    /// Called at each iteration of the main loop, e.g. to prevent a watchdog from giving the control
+    /// back to the firmware.
+    fn keep_alive(&mut self) -> Result {
+        Ok(())
+    }
+
+    /// Read the byte at `register` of the EC.
+    fn read_register(&self, _register: u8) -> Result<u8> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "The fan backend does not give access to the EC",
+        ))
+    }
+}
+
+impl<B: FanBackend + ?Sized> FanBackend for Box<B> {
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result {
+        (**self).refresh_config(c)
+    }
+
+    fn init(&mut self) -> Result {
+        (**self).init()
+    }
+
+    fn reset(&mut self, reset_all: bool) -> Result {
+        (**self).reset(reset_all)
+    }
+
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result {
+        (**self).write_speed_percent(fan_index, speed_percent)
+    }
+
+    fn read_speed_percent(&self, fan_index: usize) -> Result<f64> {
+        (**self).read_speed_percent(fan_index)
+    }
+
+    fn verify_init(&self) -> Result<bool> {
+        (**self).verify_init()
+    }
+
+    fn verify_speed(&self, fan_index: usize) -> Result<bool> {
+        (**self).verify_speed(fan_index)
+    }
+
+    fn default_config(&self) -> Option<FanControlConfigV2> {
+        (**self).default_config()
+    }
+
+    fn keep_alive(&mut self) -> Result {
+        (**self).keep_alive()
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8> {
+        (**self).read_register(register)
+    }
+}
+
+#[derive(Debug)]
+/// Controls the fans by writing to the registers of the EC.
+pub(crate) struct ECBackend<T: RW> {
+    reader: ECReader<T>,
+    writer: ECWriter<T>,
+}
+
+impl<T: RW> ECBackend<T> {
+    pub fn new(ec_device: T) -> Self {
+        let ec_device = Rc::from(RefCell::from(ec_device));
+
+        ECBackend {
+            writer: ECWriter::new(Rc::clone(&ec_device)),
+            reader: ECReader::new(Rc::clone(&ec_device)),
+        }
+    }
+}
+
+impl<T: RW> FanBackend for ECBackend<T> {
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result {
+        self.reader
+            .refresh_config(c.read_write_words, &c.fan_configurations);
+
+        self.writer.refresh_config(
+            c.read_write_words,
+            c.register_write_configurations.clone(),
+            &c.fan_configurations,
+        )
+    }
+
+    fn init(&mut self) -> Result {
+        self.writer.init_write()
+    }
+
+    fn reset(&mut self, reset_all: bool) -> Result {
+        self.writer.reset(reset_all)
+    }
+
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result {
+        self.writer.write_speed_percent(fan_index, speed_percent)
+    }
+
+    fn read_speed_percent(&self, fan_index: usize) -> Result<f64> {
+        self.reader.read_speed_percent(fan_index)
+    }
+
+    fn verify_init(&self) -> Result<bool> {
+        self.writer.verify_init()
+    }
+
+    fn verify_speed(&self, fan_index: usize) -> Result<bool> {
+        self.writer.verify_speed(fan_index)
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8> {
+        self.reader.read_register(register)
+    }
+}
+
+#[derive(Debug)]
+/// Controls the fans with several ECs, each fan being controlled by one of them.
+/// The main EC controls the fans which are not assigned to another EC,
+/// and it is the only one to which the registers of the initialization are written.
+pub(crate) struct MultiECBackend<T: RW> {
+    /// The ECs with the indices of the fans they control (empty for the main EC, which is first).
+    ecs: Vec<(ECBackend<T>, Vec<usize>)>,
+    /// Index of the EC and index in its configuration of each fan.
+    fans: Vec<(usize, usize)>,
+}
+
+impl<T: RW> MultiECBackend<T> {
+    /// Create a backend for the `main` EC and the `others`, given with the indices of their fans.
+    pub fn new(main: T, others: Vec<(T, Vec<usize>)>) -> Self {
+        let ecs = std::iter::once((main, Vec::new()))
+            .chain(others)
+            .map(|(ec, fans)| (ECBackend::new(ec), fans))
+            .collect();
+
+        MultiECBackend {
+            ecs,
+            fans: Vec::new(),
+        }
+    }
+
+    /// Get the EC of the fan at `fan_index`, with the index of the fan in its configuration.
+    fn fan(&self, fan_index: usize) -> Result<(usize, usize)> {
+        self.fans.get(fan_index).copied().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("There is no fan with index {}", fan_index),
+            )
+        })
+    }
+}
+
+impl<T: RW> FanBackend for MultiECBackend<T> {
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result {
+        let mut assigned = vec![false; c.fan_configurations.len()];
+        for &i in self.ecs.iter().flat_map(|(_, fans)| fans) {
+            match assigned.get_mut(i) {
+                Some(true) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("The fan with index {} is assigned to several ECs", i),
+                    ))
+                }
+                Some(assigned) => *assigned = true,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!(
+                            "The fan with index {} is assigned to an EC but only {} fans are configured",
+                            i,
+                            c.fan_configurations.len()
+                        ),
+                    ))
+                }
+            }
+        }
+
+        let mut counts = vec![0; self.ecs.len()];
+        self.fans = (0..c.fan_configurations.len())
+            .map(|i| {
+                let ec = self
+                    .ecs
+                    .iter()
+                    .position(|(_, fans)| fans.contains(&i))
+                    .unwrap_or(0);
+                counts[ec] += 1;
+                (ec, counts[ec] - 1)
+            })
+            .collect();
+
+        for (i, (ec, _)) in self.ecs.iter_mut().enumerate() {
+            let config = FanControlConfigV2 {
+                fan_configurations: c
+                    .fan_configurations
+                    .iter()
+                    .zip(&self.fans)
+                    .filter(|(_, (e, _))| *e == i)
+                    .map(|(f, _)| f.clone())
+                    .collect(),
+                register_write_configurations: if i == 0 {
+                    c.register_write_configurations.clone()
+                } else {
+                    None
+                },
+                ..c.clone()
+            };
+            ec.refresh_config(&config)?;
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result {
+        self.ecs.iter_mut().try_for_each(|(ec, _)| ec.init())
+    }
+
+    fn reset(&mut self, reset_all: bool) -> Result {
+        self.ecs
+            .iter_mut()
+            .try_for_each(|(ec, _)| ec.reset(reset_all))
+    }
+
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result {
+        let (ec, i) = self.fan(fan_index)?;
+        self.ecs[ec].0.write_speed_percent(i, speed_percent)
+    }
+
+    fn read_speed_percent(&self, fan_index: usize) -> Result<f64> {
+        let (ec, i) = self.fan(fan_index)?;
+        self.ecs[ec].0.read_speed_percent(i)
+    }
+
+    fn verify_init(&self) -> Result<bool> {
+        for (ec, _) in &self.ecs {
+            if !ec.verify_init()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn verify_speed(&self, fan_index: usize) -> Result<bool> {
+        let (ec, i) = self.fan(fan_index)?;
+        self.ecs[ec].0.verify_speed(i)
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8> {
+        self.ecs[0].0.read_register(register)
+    }
+}
+
+/// Create a fan control configuration with `fans` fans, for the tests of the backends.
+#[cfg(test)]
+pub(super) fn fans_config(fans: usize) -> FanControlConfigV2 {
+    let fan = crate::nbfc::FanConfiguration {
+        read_register: 0,
+        write_register: 0,
+        min_speed_value: 0,
+        max_speed_value: 255,
+        independent_read_min_max_values: false,
+        min_speed_value_read: 0,
+        max_speed_value_read: 0,
+        reset_required: false,
+        fan_speed_reset_value: None,
+        fan_display_name: None,
+        temperature_thresholds: Vec::new(),
+        fan_speed_percentage_overrides: None,
+    };
+    FanControlConfigV2 {
+        fan_configurations: vec![fan; fans],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn multiple_ecs() {
+        let mut main = Cursor::new(vec![0u8; 256]);
+        let mut other = Cursor::new(vec![0u8; 256]);
+        let mut c = fans_config(3);
+        for (i, fan) in c.fan_configurations.iter_mut().enumerate() {
+            fan.read_register = 0x10 + i as u8;
+            fan.write_register = 0x10 + i as u8;
+        }
+
+        {
+            let mut backend = MultiECBackend::new(&mut main, vec![(&mut other, vec![1])]);
+            backend.refresh_config(&c).unwrap();
+            for i in 0..3 {
+                backend.write_speed_percent(i, 100.0).unwrap();
+            }
+            assert_eq!(backend.read_speed_percent(1).unwrap(), 100.0);
+            assert!(backend.verify_speed(1).unwrap());
+            assert!(backend.write_speed_percent(3, 100.0).is_err());
+        }
+
+        // The second fan is written to the other EC, at the same register.
+        assert_eq!(&main.get_ref()[0x10..0x13], &[255, 0, 255]);
+        assert_eq!(&other.get_ref()[0x10..0x13], &[0, 255, 0]);
+
+        // The fans must exist and be assigned to one EC only.
+        let mut third = Cursor::new(vec![0u8; 256]);
+        let mut backend = MultiECBackend::new(
+            &mut main,
+            vec![(&mut other, vec![1]), (&mut third, vec![1, 2])],
+        );
+        assert_eq!(
+            backend.refresh_config(&c).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        let mut backend = MultiECBackend::new(&mut main, vec![(&mut other, vec![3])]);
+        assert_eq!(
+            backend.refresh_config(&c).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
@@ -1,16 +1,17 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
-use snafu::{ResultExt, Snafu};
+use log::{debug, error};
+use snafu::Snafu;
 
-use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::rc::Rc;
 use std::time::Duration;
 
-use super::read::ECReader;
-use super::write::ECWriter;
-use super::RW;
+use super::backend::FanBackend;
+use super::health::{FanHealth, HealthMonitor};
+use super::pid::PidController;
+use super::raw_port::PortError;
+use crate::config::service::{ControlMode, FanSettings, StallDetection};
 use crate::nbfc::*;
 
 #[derive(Debug, Snafu)]
@@ -20,43 +21,111 @@ pub(crate) enum ECError {
 
     #[snafu(display("An I/O error occured with the reader: {}", source))]
     Reader { source: std::io::Error },
+
+    #[snafu(display("An error occured while accessing to the EC: {}", source))]
+    Port { source: PortError },
+
+    #[snafu(display("The fan #{} has no temperature thresholds", index))]
+    EmptyThresholds { index: usize },
+}
+
+impl ECError {
+    /// Wrap an error of the writer, keeping apart the errors of the ports of the EC.
+    fn writer(e: std::io::Error) -> Self {
+        PortError::from_io(e).map_or_else(
+            |source| ECError::Writer { source },
+            |source| ECError::Port { source },
+        )
+    }
+
+    /// Wrap an error of the reader, keeping apart the errors of the ports of the EC.
+    fn reader(e: std::io::Error) -> Self {
+        PortError::from_io(e).map_or_else(
+            |source| ECError::Reader { source },
+            |source| ECError::Port { source },
+        )
+    }
 }
 
 type Result<T = ()> = std::result::Result<T, ECError>;
 
+/// Difference (in degrees Celsius) below the critical temperature at which the critical mode is left.
+const CRITICAL_INTERVAL: f64 = 10.0;
+
+/// Get the temperature used to control the fans.
+/// An unknown (NaN) temperature is considered higher than any threshold,
+/// so the fans are not slowed down by a faulty sensor.
+fn control_temp(temp: f64) -> f64 {
+    if temp.is_nan() {
+        f64::INFINITY
+    } else {
+        temp
+    }
+}
+
 /// Holds useful information about a fan (not used by the writer or the reader).
 #[derive(Debug)]
 pub(crate) struct FanConfig {
     pub name: String,
     pub thresholds: Vec<TemperatureThreshold>,
     pub current_threshold: usize,
+    /// Control mode used during the last computation of the speed.
+    pub control_mode: ControlMode,
+    pub pid: PidController,
+    /// Speed which the fan should reach.
+    pub target_speed: Option<f64>,
+    /// Last speed written to the EC.
+    pub written_speed: Option<f64>,
+    pub health: HealthMonitor,
+}
+
+/// Counts the values overwritten in the EC which have been detected when verifying the writes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct WriteCounters {
+    pub verifications: u64,
+    /// Times the speed registers have been overwritten.
+    pub speed_overwrites: u64,
+    /// Times the initialization registers have been overwritten, which required a re-initialization.
+    pub reinitializations: u64,
 }
 
-/// Manages accesses to the EC.
+/// Manages the control of the fans, which are accessed through a backend (usually the EC).
 #[derive(Debug)]
-pub(crate) struct ECManager<T: RW> {
+pub(crate) struct ECManager<B: FanBackend> {
     pub poll_interval: Duration,
     pub fan_configs: Vec<FanConfig>,
     pub critical_temperature: u8,
-    reader: ECReader<T>,
-    writer: ECWriter<T>,
+    pub write_counters: WriteCounters,
+    backend: B,
 }
 
-impl<T: RW> ECManager<T> {
-    pub fn new(ec_device: T) -> Self {
-        let ec_device = Rc::from(RefCell::from(ec_device));
-
+impl<B: FanBackend> ECManager<B> {
+    pub fn new(backend: B) -> Self {
         ECManager {
             poll_interval: Duration::from_nanos(0),
             fan_configs: Vec::new(),
             critical_temperature: 0,
-            writer: ECWriter::new(Rc::clone(&ec_device)),
-            reader: ECReader::new(Rc::clone(&ec_device)),
+            write_counters: WriteCounters::default(),
+            backend,
         }
     }
 
+    /// Get the fan control configuration provided by the backend, if any.
+    pub fn backend_config(&self) -> Option<FanControlConfigV2> {
+        self.backend.default_config()
+    }
+
     /// Refresh the fan(s) configuration and initialize the writer according to this config.
     pub fn refresh_control_config(&mut self, c: FanControlConfigV2) -> Result {
+        // The speeds are computed from the thresholds.
+        if let Some(i) = c
+            .fan_configurations
+            .iter()
+            .position(|f| f.temperature_thresholds.is_empty())
+        {
+            return EmptyThresholds { index: i + 1 }.fail();
+        }
+
         self.fan_configs = c
             .fan_configurations
             .iter()
@@ -69,6 +138,11 @@ impl<T: RW> ECManager<T> {
                         .unwrap_or(format!("Fan #{}", acc)),
                     thresholds: f.temperature_thresholds.to_owned(),
                     current_threshold: 0,
+                    control_mode: ControlMode::default(),
+                    pid: PidController::default(),
+                    target_speed: None,
+                    written_speed: None,
+                    health: HealthMonitor::default(),
                 })
             })
             .collect();
@@ -80,44 +154,92 @@ impl<T: RW> ECManager<T> {
             .iter_mut()
             .for_each(|c| c.thresholds.sort());
 
-        self.reader
-            .refresh_config(c.read_write_words, &c.fan_configurations);
+        self.backend.refresh_config(&c).map_err(ECError::writer)
+    }
 
-        self.writer
-            .refresh_config(
-                c.read_write_words,
-                c.register_write_configurations,
-                &c.fan_configurations,
-            )
-            .context(Writer {})
+    /// Compute the speed of the fan at `fan_index` for the temperature `temp`,
+    /// according to its control mode in `settings`.
+    /// `dt` is the time elapsed since the last computation.
+    /// Returns `None` if the speed doesn't need to be written.
+    pub fn compute_fan_speed(
+        &mut self,
+        fan_index: usize,
+        temp: f64,
+        settings: &FanSettings,
+        dt: Duration,
+    ) -> Option<f64> {
+        let temp = control_temp(temp);
+        let mode_changed = self.fan_configs[fan_index].control_mode != settings.control_mode;
+        if mode_changed {
+            let fan_config = &mut self.fan_configs[fan_index];
+            fan_config.control_mode = settings.control_mode;
+            fan_config.pid.reset();
+        }
+
+        match settings.control_mode {
+            ControlMode::Threshold => {
+                // If the function returns `true`, the threshold has changed.
+                // Else, there is nothing to change unless the mode has just been selected.
+                if self.refresh_fan_threshold(temp, fan_index) || mode_changed {
+                    let fan_config = &self.fan_configs[fan_index];
+                    let threshold = fan_config.current_threshold;
+                    debug!("Selected threshold #{}", threshold);
+                    // The fan is set to its maximum speed if it has no thresholds.
+                    Some(
+                        fan_config
+                            .thresholds
+                            .get(threshold)
+                            .map_or(100.0, |t| t.fan_speed.into()),
+                    )
+                } else {
+                    None
+                }
+            }
+            ControlMode::Curve => Some(self.interpolate_fan_speed(temp, fan_index)),
+            // The state of the controller is kept for the next finite temperature.
+            ControlMode::Pid if !temp.is_finite() => Some(if temp > 0.0 {
+                settings.pid.max_output
+            } else {
+                settings.pid.min_output
+            }),
+            ControlMode::Pid => Some(self.fan_configs[fan_index].pid.update(
+                &settings.pid,
+                temp,
+                dt,
+            )),
+        }
     }
 
     /// Refresh the index of the current fan threshold according to the temperature (if necessary).
     /// Returns false if the threshold didn't need change.
     ///
-    /// # Panics
-    ///
-    /// Panics if the temperature cannot be converted to `u8`.
-    /// Panics if the thresholds has no elements.
+    /// The temperature is compared to the thresholds without truncation.
+    /// A negative temperature selects the first threshold,
+    /// while an unknown (NaN) temperature or one above 255°C selects the last one.
+    /// Nothing is selected if the fan has no thresholds.
     pub fn refresh_fan_threshold(&mut self, temp: f64, fan_index: usize) -> bool {
-        let temp = temp as u8;
+        let temp = control_temp(temp);
         let fan_config = &mut self.fan_configs[fan_index];
         let thresholds = &fan_config.thresholds;
         let current = &mut fan_config.current_threshold;
-
-        if temp >= thresholds.last().unwrap().up_threshold {
+        let up = |t: &TemperatureThreshold| f64::from(t.up_threshold);
+        let down = |t: &TemperatureThreshold| f64::from(t.down_threshold);
+
+        let last = match thresholds.last() {
+            Some(last) => last,
+            None => return false,
+        };
+        if temp >= up(last) {
             *current = thresholds.len() - 1;
-        } else if temp >= thresholds[*current].down_threshold
-            && temp <= thresholds[*current].up_threshold
-        {
+        } else if temp >= down(&thresholds[*current]) && temp <= up(&thresholds[*current]) {
             return false;
-        } else if matches!(thresholds.iter().find(|t| t.down_threshold != 0), Some(thr) if temp <= thr.down_threshold)
+        } else if matches!(thresholds.iter().find(|t| t.down_threshold != 0), Some(thr) if temp <= down(thr))
             || thresholds.len() == 1
         {
             *current = 0;
         } else if let Ok(i) = thresholds.binary_search_by(|el| match el {
-            _t if _t.down_threshold > temp => Ordering::Greater,
-            _t if _t.up_threshold < temp => Ordering::Less,
+            _t if down(_t) > temp => Ordering::Greater,
+            _t if up(_t) < temp => Ordering::Less,
             _ => Ordering::Equal,
         }) {
             *current = i;
@@ -126,27 +248,213 @@ impl<T: RW> ECManager<T> {
         true
     }
 
+    /// Compute the fan speed for the temperature by interpolating linearly between the thresholds,
+    /// where each threshold is a control point at its `UpThreshold`.
+    /// The speed is constant below the first control point and above the last one.
+    /// The fan is set to its maximum speed if it has no thresholds.
+    pub fn interpolate_fan_speed(&self, temp: f64, fan_index: usize) -> f64 {
+        let temp = control_temp(temp);
+        let thresholds = &self.fan_configs[fan_index].thresholds;
+        let (first, last) = match (thresholds.first(), thresholds.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 100.0,
+        };
+
+        if temp <= first.up_threshold.into() {
+            return first.fan_speed.into();
+        }
+
+        for points in thresholds.windows(2) {
+            let (low, high) = (&points[0], &points[1]);
+            let (low_temp, high_temp) = (f64::from(low.up_threshold), f64::from(high.up_threshold));
+
+            if temp <= high_temp {
+                let ratio = if high_temp > low_temp {
+                    (temp - low_temp) / (high_temp - low_temp)
+                } else {
+                    1.0
+                };
+
+                return f64::from(low.fan_speed)
+                    + (f64::from(high.fan_speed) - f64::from(low.fan_speed)) * ratio;
+            }
+        }
+
+        last.fan_speed.into()
+    }
+
+    /// Check if the temperature `temp` is critical, where `critical` is the current critical state.
+    /// The critical mode is left once the temperature is `CRITICAL_INTERVAL` degrees below
+    /// the critical temperature, and an unknown (NaN) temperature is critical.
+    pub fn is_critical(&self, temp: f64, critical: bool) -> bool {
+        let temp = control_temp(temp);
+        let critical_temperature = f64::from(self.critical_temperature);
+
+        if critical {
+            critical_temperature - temp <= CRITICAL_INTERVAL
+        } else {
+            temp >= critical_temperature
+        }
+    }
+
     /// Write the speed percent to the EC for the fan specified by `fan_index`.
     pub fn write_fan_speed(&mut self, fan_index: usize, speed_percent: f64) -> Result {
-        self.writer
+        self.backend
             .write_speed_percent(fan_index, speed_percent)
-            .context(Writer {})
+            .map_err(ECError::writer)?;
+        self.fan_configs[fan_index].written_speed = Some(speed_percent);
+        Ok(())
+    }
+
+    /// Write a speed for the fan specified by `fan_index` which converges towards its target speed,
+    /// limited by the ramp rates of `settings` for the elapsed time `dt`.
+    /// `target` replaces the target speed of the fan when it is provided, and is always written when
+    /// there is no ramp limit. Otherwise, nothing is written once the target has been reached.
+    pub fn write_fan_speed_ramped(
+        &mut self,
+        fan_index: usize,
+        target: Option<f64>,
+        settings: &FanSettings,
+        dt: Duration,
+    ) -> Result {
+        let fan_config = &mut self.fan_configs[fan_index];
+        if target.is_some() {
+            fan_config.target_speed = target;
+        }
+
+        let target_speed = match fan_config.target_speed {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let speed = match fan_config.written_speed {
+            Some(written_speed) => ramp_speed(
+                written_speed,
+                target_speed,
+                settings.ramp_up,
+                settings.ramp_down,
+                dt,
+            ),
+            None => target_speed,
+        };
+
+        if target.is_some() || fan_config.written_speed != Some(speed) {
+            debug!(
+                "Ramped fan speed for {} with index {}: {}",
+                fan_config.name, fan_index, speed
+            );
+            self.write_fan_speed(fan_index, speed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Verify that the EC still holds the written values.
+    /// The EC is initialized again when the firmware has overwritten the initialization registers,
+    /// and the speeds which have been overwritten are written again.
+    pub fn verify_writes(&mut self) -> Result {
+        self.write_counters.verifications += 1;
+
+        let reinit = !self.backend.verify_init().map_err(ECError::reader)?;
+        if reinit {
+            error!("The initialization registers have been overwritten, initializing the EC again");
+            self.write_counters.reinitializations += 1;
+            return self.init_ec();
+        }
+
+        for i in 0..self.fan_configs.len() {
+            let written_speed = match self.fan_configs[i].written_speed {
+                Some(s) => s,
+                None => continue,
+            };
+            if !self.backend.verify_speed(i).map_err(ECError::reader)? {
+                error!(
+                    "The speed of {} with index {} has been overwritten, writing it again",
+                    self.fan_configs[i].name, i
+                );
+                self.write_counters.speed_overwrites += 1;
+                self.write_fan_speed(i, written_speed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Initialize the EC again and write the last speeds, e.g. after the control has been given
+    /// back to the firmware.
+    pub fn init_ec(&mut self) -> Result {
+        self.backend.init().map_err(ECError::writer)?;
+
+        // The initialization may reset the speed registers.
+        for i in 0..self.fan_configs.len() {
+            if let Some(written_speed) = self.fan_configs[i].written_speed {
+                self.write_fan_speed(i, written_speed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep the control of the fans, called at each iteration of the main loop.
+    pub fn keep_alive(&mut self) -> Result {
+        self.backend.keep_alive().map_err(ECError::writer)
     }
 
     /// Reset the EC, including non-required registers when `reset_all` is true.
     pub fn reset_ec(&mut self, reset_all: bool) -> Result {
-        self.writer.reset(reset_all).context(Writer {})
+        self.backend.reset(reset_all).map_err(ECError::writer)
+    }
+
+    /// Update the health of the fan specified by `fan_index` with the speed `read` from the EC,
+    /// `dt` being the time elapsed since the last update.
+    pub fn update_fan_health(
+        &mut self,
+        fan_index: usize,
+        read: f64,
+        settings: &StallDetection,
+        dt: Duration,
+    ) -> FanHealth {
+        let fan_config = &mut self.fan_configs[fan_index];
+        let requested = fan_config.written_speed.unwrap_or(0.0);
+        fan_config.health.update(settings, requested, read, dt)
     }
 
     /// Read the speed percent from the EC for the fan specified by `fan_index`.
     pub fn read_fan_speed(&mut self, fan_index: usize) -> Result<f64> {
-        self.reader.read_speed_percent(fan_index).context(Reader {})
+        self.backend
+            .read_speed_percent(fan_index)
+            .map_err(ECError::reader)
+    }
+
+    /// Read the byte at `register` of the EC.
+    pub fn read_register(&self, register: u8) -> Result<u8> {
+        self.backend
+            .read_register(register)
+            .map_err(ECError::reader)
+    }
+}
+
+/// Move the speed `from` towards `to`, by at most `ramp_up` or `ramp_down` (percent per second)
+/// for the duration `dt`. There is no limit if the ramp is `None`.
+fn ramp_speed(
+    from: f64,
+    to: f64,
+    ramp_up: Option<f64>,
+    ramp_down: Option<f64>,
+    dt: Duration,
+) -> f64 {
+    let dt = dt.as_secs_f64();
+
+    if to > from {
+        ramp_up.map_or(to, |rate| to.min(from + rate.abs() * dt))
+    } else {
+        ramp_down.map_or(to, |rate| to.max(from - rate.abs() * dt))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ec_control::backend::ECBackend;
     use once_cell::sync::Lazy;
     use std::io::{Cursor, Read};
 
@@ -174,7 +482,7 @@ mod tests {
         CONFIGS_PARSED.iter().for_each(|c| {
             let ec = Cursor::new(vec![0u8; 256]);
 
-            let mut manager = ECManager::new(ec);
+            let mut manager = ECManager::new(ECBackend::new(ec));
 
             manager.refresh_control_config(c.clone()).unwrap();
 
@@ -192,7 +500,7 @@ mod tests {
         CONFIGS_PARSED.iter().for_each(|c| {
             let ec = Cursor::new(vec![0u8; 256]);
 
-            let mut manager = ECManager::new(ec);
+            let mut manager = ECManager::new(ECBackend::new(ec));
 
             manager.refresh_control_config(c.clone()).unwrap();
 
@@ -239,6 +547,309 @@ mod tests {
         });
     }
 
+    /// Create a manager with one fan whose thresholds are at 0-50°C, 45-60°C and 55-80°C.
+    fn manager_with_thresholds() -> ECManager<ECBackend<Cursor<Vec<u8>>>> {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ECBackend::new(ec));
+        manager.fan_configs = vec![FanConfig {
+            name: "Fan".to_string(),
+            thresholds: vec![
+                TemperatureThreshold {
+                    up_threshold: 50,
+                    down_threshold: 0,
+                    fan_speed: 0.0,
+                },
+                TemperatureThreshold {
+                    up_threshold: 60,
+                    down_threshold: 45,
+                    fan_speed: 20.0,
+                },
+                TemperatureThreshold {
+                    up_threshold: 80,
+                    down_threshold: 55,
+                    fan_speed: 100.0,
+                },
+            ],
+            current_threshold: 0,
+            control_mode: ControlMode::Curve,
+            pid: PidController::default(),
+            target_speed: None,
+            written_speed: None,
+            health: HealthMonitor::default(),
+        }];
+        manager.critical_temperature = 90;
+        manager
+    }
+
+    #[test]
+    fn interpolate_speed() {
+        let manager = manager_with_thresholds();
+
+        assert_eq!(manager.interpolate_fan_speed(20.0, 0), 0.0);
+        assert_eq!(manager.interpolate_fan_speed(50.0, 0), 0.0);
+        assert_eq!(manager.interpolate_fan_speed(55.0, 0), 10.0);
+        assert_eq!(manager.interpolate_fan_speed(60.0, 0), 20.0);
+        assert_eq!(manager.interpolate_fan_speed(70.0, 0), 60.0);
+        assert_eq!(manager.interpolate_fan_speed(80.0, 0), 100.0);
+        assert_eq!(manager.interpolate_fan_speed(95.0, 0), 100.0);
+    }
+
+    #[test]
+    fn fractional_and_invalid_temps() {
+        let mut manager = manager_with_thresholds();
+
+        // The temperatures are not truncated.
+        assert!(!manager.refresh_fan_threshold(50.0, 0));
+        assert!(manager.refresh_fan_threshold(50.5, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+        assert_eq!(manager.interpolate_fan_speed(50.5, 0), 1.0);
+
+        manager.refresh_fan_threshold(-20.0, 0);
+        assert_eq!(manager.fan_configs[0].current_threshold, 0);
+        assert_eq!(manager.interpolate_fan_speed(-20.0, 0), 0.0);
+
+        manager.refresh_fan_threshold(300.0, 0);
+        assert_eq!(manager.fan_configs[0].current_threshold, 2);
+
+        // An unknown temperature is handled as the hottest one.
+        manager.refresh_fan_threshold(-20.0, 0);
+        manager.refresh_fan_threshold(f64::NAN, 0);
+        assert_eq!(manager.fan_configs[0].current_threshold, 2);
+        assert_eq!(manager.interpolate_fan_speed(f64::NAN, 0), 100.0);
+
+        let dt = Duration::from_secs(1);
+        let pid = FanSettings {
+            control_mode: ControlMode::Pid,
+            ..Default::default()
+        };
+        assert_eq!(
+            manager.compute_fan_speed(0, f64::NAN, &pid, dt),
+            Some(pid.pid.max_output)
+        );
+        assert_eq!(
+            manager.compute_fan_speed(0, f64::NEG_INFINITY, &pid, dt),
+            Some(pid.pid.min_output)
+        );
+        let speed = manager.compute_fan_speed(0, 40.0, &pid, dt).unwrap();
+        assert!(speed.is_finite());
+    }
+
+    #[test]
+    fn critical() {
+        let manager = manager_with_thresholds();
+
+        assert!(!manager.is_critical(89.9, false));
+        assert!(manager.is_critical(90.0, false));
+        assert!(manager.is_critical(300.0, false));
+        assert!(!manager.is_critical(-5.0, false));
+        assert!(manager.is_critical(f64::NAN, false));
+
+        // The critical mode is left 10°C below the critical temperature.
+        assert!(manager.is_critical(80.5, true));
+        assert!(!manager.is_critical(79.9, true));
+        assert!(!manager.is_critical(-5.0, true));
+        assert!(manager.is_critical(f64::NAN, true));
+    }
+
+    #[test]
+    fn empty_thresholds() {
+        let mut manager = manager_with_thresholds();
+        manager.fan_configs[0].thresholds.clear();
+
+        assert!(!manager.refresh_fan_threshold(60.0, 0));
+        assert_eq!(manager.interpolate_fan_speed(60.0, 0), 100.0);
+        let dt = Duration::from_secs(1);
+        let curve = FanSettings {
+            control_mode: ControlMode::Curve,
+            ..Default::default()
+        };
+        assert_eq!(manager.compute_fan_speed(0, 60.0, &curve, dt), Some(100.0));
+        assert_eq!(
+            manager.compute_fan_speed(0, 60.0, &FanSettings::default(), dt),
+            Some(100.0)
+        );
+
+        // The configurations whose fans have no thresholds are rejected.
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.fan_configurations[0].temperature_thresholds.clear();
+        assert!(matches!(
+            manager.refresh_control_config(config),
+            Err(ECError::EmptyThresholds { index: 1 })
+        ));
+        assert_eq!(manager.fan_configs[0].name, "Fan");
+    }
+
+    #[test]
+    fn interpolate_speed_bounds() {
+        CONFIGS_PARSED.iter().for_each(|c| {
+            let ec = Cursor::new(vec![0u8; 256]);
+
+            let mut manager = ECManager::new(ECBackend::new(ec));
+
+            manager.refresh_control_config(c.clone()).unwrap();
+
+            for (i, fan) in manager.fan_configs.iter().enumerate() {
+                let min_speed = fan
+                    .thresholds
+                    .iter()
+                    .map(|t| f64::from(t.fan_speed))
+                    .fold(f64::INFINITY, f64::min);
+                let max_speed = fan
+                    .thresholds
+                    .iter()
+                    .map(|t| f64::from(t.fan_speed))
+                    .fold(f64::NEG_INFINITY, f64::max);
+
+                for temp in 0..120 {
+                    let speed = manager.interpolate_fan_speed(temp as f64, i);
+                    assert!(speed >= min_speed && speed <= max_speed);
+                }
+
+                assert_eq!(
+                    manager.interpolate_fan_speed(255.0, i),
+                    f64::from(fan.thresholds.last().unwrap().fan_speed)
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn compute_speed_mode_switch() {
+        CONFIGS_PARSED.iter().for_each(|c| {
+            let ec = Cursor::new(vec![0u8; 256]);
+
+            let mut manager = ECManager::new(ECBackend::new(ec));
+
+            manager.refresh_control_config(c.clone()).unwrap();
+
+            let dt = Duration::from_secs(1);
+            let threshold = FanSettings::default();
+            let pid = FanSettings {
+                control_mode: ControlMode::Pid,
+                ..Default::default()
+            };
+
+            for i in 0..manager.fan_configs.len() {
+                let thresholds = &manager.fan_configs[i].thresholds;
+                let last_up = thresholds.last().unwrap().up_threshold;
+                // A temperature which stays inside the first threshold.
+                let steady_temp = thresholds[0].down_threshold;
+                if thresholds.len() == 1 || steady_temp >= last_up {
+                    continue;
+                }
+                let steady_temp = steady_temp.into();
+                let first_speed = thresholds[0].fan_speed;
+
+                // Nothing to write while the threshold is the same.
+                assert!(manager
+                    .compute_fan_speed(i, steady_temp, &threshold, dt)
+                    .is_none());
+
+                let speed = manager.compute_fan_speed(i, 95.0, &pid, dt).unwrap();
+                assert!(speed > 0.0);
+                assert_eq!(manager.fan_configs[i].control_mode, ControlMode::Pid);
+
+                // The speed of the threshold is written again when switching back.
+                assert_eq!(
+                    manager.compute_fan_speed(i, steady_temp, &threshold, dt),
+                    Some(first_speed.into())
+                );
+                assert!(manager
+                    .compute_fan_speed(i, steady_temp, &threshold, dt)
+                    .is_none());
+            }
+        });
+    }
+
+    #[test]
+    fn ramp() {
+        let second = Duration::from_secs(1);
+
+        assert_eq!(ramp_speed(20.0, 80.0, None, None, second), 80.0);
+        assert_eq!(ramp_speed(20.0, 80.0, Some(10.0), None, second), 30.0);
+        assert_eq!(ramp_speed(20.0, 80.0, Some(10.0), None, second * 3), 50.0);
+        assert_eq!(ramp_speed(75.0, 80.0, Some(10.0), None, second), 80.0);
+        assert_eq!(ramp_speed(80.0, 20.0, Some(10.0), None, second), 20.0);
+        assert_eq!(ramp_speed(80.0, 20.0, None, Some(5.0), second * 2), 70.0);
+        assert_eq!(ramp_speed(22.0, 20.0, None, Some(5.0), second), 20.0);
+    }
+
+    #[test]
+    fn verify_writes() {
+        CONFIGS_PARSED.iter().for_each(|c| {
+            let ec = Cursor::new(vec![0u8; 256]);
+
+            let mut manager = ECManager::new(ECBackend::new(ec));
+
+            manager.refresh_control_config(c.clone()).unwrap();
+
+            for i in 0..manager.fan_configs.len() {
+                manager.write_fan_speed(i, 40.0).unwrap();
+            }
+            manager.verify_writes().unwrap();
+            manager.verify_writes().unwrap();
+
+            assert_eq!(
+                manager.write_counters,
+                WriteCounters {
+                    verifications: 2,
+                    speed_overwrites: 0,
+                    reinitializations: 0,
+                }
+            );
+            assert!(manager
+                .fan_configs
+                .iter()
+                .all(|f| f.written_speed == Some(40.0)));
+        });
+    }
+
+    #[test]
+    fn write_ramped_speed() {
+        CONFIGS_PARSED.iter().for_each(|c| {
+            let ec = Cursor::new(vec![0u8; 256]);
+
+            let mut manager = ECManager::new(ECBackend::new(ec));
+
+            manager.refresh_control_config(c.clone()).unwrap();
+
+            let dt = Duration::from_secs(1);
+            let settings = FanSettings {
+                ramp_up: Some(20.0),
+                ramp_down: Some(10.0),
+                ..Default::default()
+            };
+
+            for i in 0..manager.fan_configs.len() {
+                // The first speed is written directly.
+                manager
+                    .write_fan_speed_ramped(i, Some(30.0), &settings, dt)
+                    .unwrap();
+                assert_eq!(manager.fan_configs[i].written_speed, Some(30.0));
+
+                manager
+                    .write_fan_speed_ramped(i, Some(100.0), &settings, dt)
+                    .unwrap();
+                assert_eq!(manager.fan_configs[i].written_speed, Some(50.0));
+
+                // It keeps converging without a new target.
+                for excepted in [70.0, 90.0, 100.0, 100.0] {
+                    manager
+                        .write_fan_speed_ramped(i, None, &settings, dt)
+                        .unwrap();
+                    assert_eq!(manager.fan_configs[i].written_speed, Some(excepted));
+                }
+
+                // The critical mode bypasses the ramp, then the speed decreases gradually.
+                manager.write_fan_speed(i, 100.0).unwrap();
+                manager
+                    .write_fan_speed_ramped(i, Some(0.0), &settings, dt)
+                    .unwrap();
+                assert_eq!(manager.fan_configs[i].written_speed, Some(90.0));
+            }
+        });
+    }
+
     // #[test]
     // fn requests() {
 
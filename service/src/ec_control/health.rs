@@ -0,0 +1,150 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::fmt;
+use std::time::Duration;
+
+use crate::config::service::StallDetection;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+/// Describe whether a fan follows the speed written to the EC.
+pub(crate) enum FanHealth {
+    #[default]
+    Ok,
+    /// The fan does not spin while a speed has been requested.
+    Stalled,
+    /// The fan does not reach the requested speed.
+    OffTarget,
+}
+
+impl fmt::Display for FanHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Tracks for how long a fan has not followed the requested speed.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct HealthMonitor {
+    stalled: Duration,
+    off_target: Duration,
+}
+
+impl HealthMonitor {
+    /// Update the health of the fan with the speed `read` from the EC while the speed `requested`
+    /// has been written for the duration `dt`.
+    pub fn update(
+        &mut self,
+        settings: &StallDetection,
+        requested: f64,
+        read: f64,
+        dt: Duration,
+    ) -> FanHealth {
+        if !settings.enabled {
+            *self = HealthMonitor::default();
+            return FanHealth::Ok;
+        }
+
+        if requested > settings.stall_speed && read <= settings.stall_speed {
+            self.stalled += dt;
+        } else {
+            self.stalled = Duration::ZERO;
+        }
+
+        if settings.check_target && (requested - read).abs() > settings.target_tolerance {
+            self.off_target += dt;
+        } else {
+            self.off_target = Duration::ZERO;
+        }
+
+        if self.stalled >= Duration::from_secs(settings.stall_delay) {
+            FanHealth::Stalled
+        } else if self.off_target >= Duration::from_secs(settings.target_delay) {
+            FanHealth::OffTarget
+        } else {
+            FanHealth::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn settings() -> StallDetection {
+        StallDetection {
+            stall_delay: 3,
+            check_target: true,
+            target_delay: 5,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stalled() {
+        let mut monitor = HealthMonitor::default();
+        let settings = settings();
+
+        assert_eq!(monitor.update(&settings, 50.0, 0.0, SECOND), FanHealth::Ok);
+        assert_eq!(monitor.update(&settings, 50.0, 0.0, SECOND), FanHealth::Ok);
+        assert_eq!(
+            monitor.update(&settings, 50.0, 0.0, SECOND),
+            FanHealth::Stalled
+        );
+        assert_eq!(monitor.update(&settings, 50.0, 45.0, SECOND), FanHealth::Ok);
+
+        // The fan is not expected to spin.
+        for _ in 0..10 {
+            assert_eq!(monitor.update(&settings, 0.0, 0.0, SECOND), FanHealth::Ok);
+        }
+    }
+
+    #[test]
+    fn off_target() {
+        let mut monitor = HealthMonitor::default();
+        let settings = settings();
+
+        for _ in 0..4 {
+            assert_eq!(
+                monitor.update(&settings, 100.0, 40.0, SECOND),
+                FanHealth::Ok
+            );
+        }
+        assert_eq!(
+            monitor.update(&settings, 100.0, 40.0, SECOND),
+            FanHealth::OffTarget
+        );
+        assert_eq!(
+            monitor.update(&settings, 100.0, 90.0, SECOND),
+            FanHealth::Ok
+        );
+    }
+
+    #[test]
+    fn default_only_stalled() {
+        let mut monitor = HealthMonitor::default();
+        let settings = StallDetection::default();
+
+        for _ in 0..120 {
+            assert_eq!(
+                monitor.update(&settings, 100.0, 40.0, SECOND),
+                FanHealth::Ok
+            );
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut monitor = HealthMonitor::default();
+        let settings = StallDetection {
+            enabled: false,
+            ..settings()
+        };
+
+        for _ in 0..10 {
+            assert_eq!(monitor.update(&settings, 100.0, 0.0, SECOND), FanHealth::Ok);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::{debug, info};
+
+use std::fs::{read_to_string, write};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::backend::FanBackend;
+use crate::hwmon::find_chip;
+use crate::nbfc::FanControlConfigV2;
+
+type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Value of `pwmN_enable` for the manual control of the fan.
+const PWM_ENABLE_MANUAL: u32 = 1;
+/// Value of `pwmN_enable` for the automatic control of the fan by the firmware or the driver.
+const PWM_ENABLE_AUTO: u32 = 2;
+const PWM_MAX: f64 = 255.0;
+
+/// Read the number in the sysfs file at `path`.
+fn read_value(path: &Path) -> Result<u32> {
+    read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+#[derive(Debug)]
+/// Holds the files of a fan and its state.
+struct PwmFan {
+    /// `pwmN` file, which holds the speed between 0 and 255.
+    pwm: PathBuf,
+    /// `pwmN_enable` file, which does not exist with some drivers.
+    enable: Option<PathBuf>,
+    /// `fanN_input` file, which holds the speed in RPM.
+    input: PathBuf,
+    /// Mode of `pwmN_enable` before the service took the control of the fan.
+    initial_mode: Option<u32>,
+    /// PWM value read back after the last write, which may have been rounded by the driver.
+    written_pwm: Option<u32>,
+}
+
+#[derive(Debug)]
+/// Controls the fans through the `pwmN` files of a hwmon chip, provided by a kernel driver
+/// (e.g. `thinkpad_acpi`, `dell-smm-hwmon` or `asus-wmi`).
+pub(crate) struct HwmonBackend {
+    /// Directory of the hwmon chips (usually `/sys/class/hwmon`).
+    root: PathBuf,
+    /// Name of the chip.
+    chip: String,
+    /// Index of the `pwmN` file of each fan, `N` being `index + 1` when it is not given.
+    pwms: Vec<u32>,
+    /// Speed of the fans (in RPM) at full PWM, used to read their speed from `fanN_input`.
+    max_rpm: Option<u32>,
+    fans: Vec<PwmFan>,
+}
+
+impl HwmonBackend {
+    /// Create a backend for the chip named `chip` in `root`.
+    /// The chip is searched when the configuration is refreshed.
+    pub fn new<P: Into<PathBuf>>(root: P, chip: &str, pwms: &[u32], max_rpm: Option<u32>) -> Self {
+        HwmonBackend {
+            root: root.into(),
+            chip: chip.to_owned(),
+            pwms: pwms.to_vec(),
+            max_rpm,
+            fans: Vec::new(),
+        }
+    }
+
+    fn fan(&self, fan_index: usize) -> Result<&PwmFan> {
+        self.fans.get(fan_index).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("There is no PWM for the fan with index {}", fan_index),
+            )
+        })
+    }
+}
+
+impl FanBackend for HwmonBackend {
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result {
+        // The fans of the previous configuration are given back to the firmware.
+        if let Err(e) = self.reset(true) {
+            debug!("Could not restore the mode of the previous fans: {}", e);
+        }
+
+        let dir = find_chip(&self.root, &self.chip)?;
+        self.fans = (0..c.fan_configurations.len())
+            .map(|i| {
+                let n = self.pwms.get(i).copied().unwrap_or(i as u32 + 1);
+                let pwm = dir.join(format!("pwm{}", n));
+                if !pwm.is_file() {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("`{}` does not exist", pwm.display()),
+                    ));
+                }
+                let enable = dir.join(format!("pwm{}_enable", n));
+
+                Ok(PwmFan {
+                    pwm,
+                    enable: Some(enable).filter(|e| e.is_file()),
+                    input: dir.join(format!("fan{}_input", n)),
+                    initial_mode: None,
+                    written_pwm: None,
+                })
+            })
+            .collect::<Result<Vec<PwmFan>>>()?;
+        info!(
+            "Controlling {} fans with the hwmon chip `{}` ({})",
+            self.fans.len(),
+            self.chip,
+            dir.display()
+        );
+
+        self.init()
+    }
+
+    fn init(&mut self) -> Result {
+        for fan in &mut self.fans {
+            if let Some(enable) = &fan.enable {
+                if fan.initial_mode.is_none() {
+                    fan.initial_mode = Some(read_value(enable)?);
+                }
+                write(enable, PWM_ENABLE_MANUAL.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, _reset_all: bool) -> Result {
+        for fan in &mut self.fans {
+            if let Some(enable) = &fan.enable {
+                // The automatic mode is used if the fan was already controlled manually.
+                let mode = fan
+                    .initial_mode
+                    .filter(|&m| m != PWM_ENABLE_MANUAL)
+                    .unwrap_or(PWM_ENABLE_AUTO);
+                write(enable, mode.to_string())?;
+                fan.initial_mode = None;
+            }
+            fan.written_pwm = None;
+        }
+        Ok(())
+    }
+
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result {
+        self.fan(fan_index)?;
+        let fan = &mut self.fans[fan_index];
+        let pwm = (speed_percent.clamp(0.0, 100.0) / 100.0 * PWM_MAX).round() as u32;
+
+        debug!("Writing {} to `{}`", pwm, fan.pwm.display());
+        write(&fan.pwm, pwm.to_string())?;
+        fan.written_pwm = Some(read_value(&fan.pwm).unwrap_or(pwm));
+        Ok(())
+    }
+
+    fn read_speed_percent(&self, fan_index: usize) -> Result<f64> {
+        let fan = self.fan(fan_index)?;
+        let percent = match self.max_rpm.filter(|&m| m > 0) {
+            Some(max_rpm) => f64::from(read_value(&fan.input)?) / f64::from(max_rpm) * 100.0,
+            None => f64::from(read_value(&fan.pwm)?) / PWM_MAX * 100.0,
+        };
+        Ok(percent.clamp(0.0, 100.0))
+    }
+
+    fn verify_init(&self) -> Result<bool> {
+        for enable in self.fans.iter().filter_map(|f| f.enable.as_ref()) {
+            if read_value(enable)? != PWM_ENABLE_MANUAL {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn verify_speed(&self, fan_index: usize) -> Result<bool> {
+        let fan = self.fan(fan_index)?;
+        match fan.written_pwm {
+            Some(written_pwm) => Ok(read_value(&fan.pwm)? == written_pwm),
+            None => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ec_control::backend::fans_config as config;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    /// Create a fake hwmon chip `thinkpad` with two fans in a temporary directory.
+    fn fake_chip(test: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("fancy-pwm-{}-{}", test, std::process::id()));
+        let chip = root.join("hwmon3");
+        create_dir_all(&chip).unwrap();
+        write(chip.join("name"), "thinkpad\n").unwrap();
+        for n in 1..=2 {
+            write(chip.join(format!("pwm{}", n)), "0\n").unwrap();
+            write(chip.join(format!("pwm{}_enable", n)), "2\n").unwrap();
+            write(chip.join(format!("fan{}_input", n)), "2700\n").unwrap();
+        }
+        root
+    }
+
+    fn read(root: &Path, file: &str) -> u32 {
+        read_value(&root.join("hwmon3").join(file)).unwrap()
+    }
+
+    #[test]
+    fn handover() {
+        let root = fake_chip("handover");
+        let mut backend = HwmonBackend::new(&root, "thinkpad", &[], None);
+        backend.refresh_config(&config(2)).unwrap();
+        assert_eq!(read(&root, "pwm1_enable"), PWM_ENABLE_MANUAL);
+        assert_eq!(read(&root, "pwm2_enable"), PWM_ENABLE_MANUAL);
+        assert!(backend.verify_init().unwrap());
+
+        // The firmware takes the control back.
+        write(root.join("hwmon3/pwm2_enable"), "2").unwrap();
+        assert!(!backend.verify_init().unwrap());
+        backend.init().unwrap();
+        assert!(backend.verify_init().unwrap());
+
+        backend.reset(true).unwrap();
+        assert_eq!(read(&root, "pwm1_enable"), PWM_ENABLE_AUTO);
+        assert_eq!(read(&root, "pwm2_enable"), PWM_ENABLE_AUTO);
+
+        // The initial mode is restored, unless it was the manual one.
+        write(root.join("hwmon3/pwm1_enable"), "0").unwrap();
+        write(root.join("hwmon3/pwm2_enable"), "1").unwrap();
+        backend.init().unwrap();
+        backend.reset(true).unwrap();
+        assert_eq!(read(&root, "pwm1_enable"), 0);
+        assert_eq!(read(&root, "pwm2_enable"), PWM_ENABLE_AUTO);
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn speeds() {
+        let root = fake_chip("speeds");
+        let mut backend = HwmonBackend::new(&root, "thinkpad", &[2], None);
+        backend.refresh_config(&config(1)).unwrap();
+        // The first fan uses `pwm2`.
+        assert_eq!(read(&root, "pwm1_enable"), PWM_ENABLE_AUTO);
+
+        backend.write_speed_percent(0, 50.0).unwrap();
+        assert_eq!(read(&root, "pwm2"), 128);
+        assert!((backend.read_speed_percent(0).unwrap() - 50.2).abs() < 0.1);
+        backend.write_speed_percent(0, 150.0).unwrap();
+        assert_eq!(read(&root, "pwm2"), 255);
+        assert!(backend.verify_speed(0).unwrap());
+
+        write(root.join("hwmon3/pwm2"), "100").unwrap();
+        assert!(!backend.verify_speed(0).unwrap());
+        assert!(backend.write_speed_percent(1, 50.0).is_err());
+        assert!(backend.read_register(0x58).is_err());
+
+        let mut backend = HwmonBackend::new(&root, "thinkpad", &[], Some(5400));
+        backend.refresh_config(&config(2)).unwrap();
+        assert_eq!(backend.read_speed_percent(1).unwrap(), 50.0);
+
+        let mut backend = HwmonBackend::new(&root, "thinkpad", &[], None);
+        assert!(backend.refresh_config(&config(3)).is_err());
+        let mut backend = HwmonBackend::new(&root, "dell_smm", &[], None);
+        assert!(backend.refresh_config(&config(1)).is_err());
+
+        remove_dir_all(&root).unwrap();
+    }
+}
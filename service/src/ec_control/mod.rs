@@ -1,16 +1,31 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+mod acpi_call;
+mod backend;
 mod ec_manager;
+mod health;
+mod hwmon_pwm;
+mod pid;
+mod probe;
 mod raw_port;
 mod read;
+mod thinkpad;
 mod write;
 use std::io::{Read, Seek, Write};
 
 type RcWrapper<T> = std::rc::Rc<std::cell::RefCell<T>>;
 
-pub(crate) use ec_manager::{ECError, ECManager};
+pub(crate) use acpi_call::AcpiCallBackend;
+pub(crate) use backend::{ECBackend, FanBackend, MultiECBackend};
+pub(crate) use ec_manager::{ECError, ECManager, WriteCounters};
+pub(crate) use health::FanHealth;
+pub(crate) use hwmon_pwm::HwmonBackend;
+pub(crate) use probe::{open_ec, ProbeError};
+pub(crate) use thinkpad::ThinkpadBackend;
 
 pub(crate) trait RW: Read + Write + Seek + std::fmt::Debug {}
 impl<T: Read + Write + Seek + std::fmt::Debug> RW for T {}
-pub(crate) use raw_port::RawPort;
+pub(crate) use raw_port::{
+    PortWait, RawPort, DEFAULT_COMMAND_PORT, DEFAULT_DATA_PORT, DEFAULT_PORT_TIMEOUT,
+};
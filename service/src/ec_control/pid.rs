@@ -0,0 +1,128 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::time::Duration;
+
+use crate::config::service::PidParameters;
+
+/// Holds the state of a PID controller which computes a fan speed to keep
+/// the temperature at a setpoint.
+///
+/// The parameters are given on each update so they can be changed at any time.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PidController {
+    integral: f64,
+    last_temp: Option<f64>,
+}
+
+impl PidController {
+    /// Compute the fan speed percentage for the temperature `temp`,
+    /// `dt` being the time elapsed since the last update.
+    pub fn update(&mut self, params: &PidParameters, temp: f64, dt: Duration) -> f64 {
+        let dt = dt.as_secs_f64();
+        // The temperature is above the setpoint when the fan needs to speed up.
+        let error = temp - params.setpoint;
+
+        // The integral term is accumulated with its gain, which avoids jumps when the gain is changed.
+        self.integral = (self.integral + params.ki * error * dt).clamp(
+            params.integral_min,
+            params.integral_max.max(params.integral_min),
+        );
+
+        // The derivative is computed on the measurement to avoid kicks when the setpoint is changed.
+        let derivative = match self.last_temp {
+            Some(last_temp) if dt > 0.0 => params.kd * (temp - last_temp) / dt,
+            _ => 0.0,
+        };
+        self.last_temp = Some(temp);
+
+        (params.kp * error + self.integral + derivative)
+            .clamp(params.min_output, params.max_output.max(params.min_output))
+    }
+
+    /// Reset the state of the controller.
+    pub fn reset(&mut self) {
+        *self = PidController::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn params() -> PidParameters {
+        PidParameters {
+            setpoint: 70.0,
+            kp: 2.0,
+            ki: 0.0,
+            kd: 0.0,
+            integral_min: 0.0,
+            integral_max: 100.0,
+            min_output: 0.0,
+            max_output: 100.0,
+        }
+    }
+
+    #[test]
+    fn proportional() {
+        let mut pid = PidController::default();
+        let params = params();
+
+        assert_eq!(pid.update(&params, 80.0, SECOND), 20.0);
+        assert_eq!(pid.update(&params, 70.0, SECOND), 0.0);
+        assert_eq!(pid.update(&params, 60.0, SECOND), 0.0);
+        assert_eq!(pid.update(&params, 200.0, SECOND), 100.0);
+    }
+
+    #[test]
+    fn integral_windup() {
+        let mut pid = PidController::default();
+        let params = PidParameters {
+            kp: 0.0,
+            ki: 1.0,
+            integral_max: 30.0,
+            ..params()
+        };
+
+        assert_eq!(pid.update(&params, 80.0, SECOND), 10.0);
+        assert_eq!(pid.update(&params, 80.0, SECOND), 20.0);
+        assert_eq!(pid.update(&params, 80.0, SECOND * 5), 30.0);
+        // The integral has been clamped, so it decreases immediately.
+        assert_eq!(pid.update(&params, 65.0, SECOND), 25.0);
+        assert_eq!(pid.update(&params, 0.0, SECOND), 0.0);
+
+        pid.reset();
+        assert_eq!(pid.update(&params, 75.0, SECOND), 5.0);
+    }
+
+    #[test]
+    fn derivative() {
+        let mut pid = PidController::default();
+        let params = PidParameters {
+            kp: 0.0,
+            kd: 10.0,
+            ..params()
+        };
+
+        assert_eq!(pid.update(&params, 70.0, SECOND), 0.0);
+        assert_eq!(pid.update(&params, 72.0, SECOND), 20.0);
+        assert_eq!(pid.update(&params, 73.0, SECOND * 2), 5.0);
+    }
+
+    #[test]
+    fn output_bounds() {
+        let mut pid = PidController::default();
+        let params = PidParameters {
+            min_output: 25.0,
+            max_output: 60.0,
+            ..params()
+        };
+
+        assert_eq!(pid.update(&params, 50.0, SECOND), 25.0);
+        assert_eq!(pid.update(&params, 75.0, SECOND), 25.0);
+        assert_eq!(pid.update(&params, 85.0, SECOND), 30.0);
+        assert_eq!(pid.update(&params, 100.0, SECOND), 60.0);
+    }
+}
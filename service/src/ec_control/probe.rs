@@ -0,0 +1,169 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::{info, warn};
+use snafu::{ResultExt, Snafu};
+
+use std::fs::{metadata, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use super::{RawPort, RW};
+use crate::config::service::{ECAccessMode, ECDevices};
+
+#[derive(Debug, Snafu)]
+/// Describe why an access mode cannot be used.
+pub(crate) enum ProbeFailure {
+    #[snafu(display("`{}` does not exist", path.display()))]
+    Missing { path: PathBuf },
+
+    #[snafu(display("`{}` is read-only (is the write support enabled?)", path.display()))]
+    ReadOnly { path: PathBuf },
+
+    #[snafu(display("`{}` could not be opened: {}", path.display(), source))]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("`{}` could not be read: {}", path.display(), source))]
+    ReadTest {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// List the failures of the access modes.
+fn list_failures(failures: &[(ECAccessMode, ProbeFailure)]) -> String {
+    failures
+        .iter()
+        .map(|(mode, f)| format!("{:?}: {}", mode, f))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ProbeError {
+    #[snafu(display(
+        "No way to access to the EC is available ({})",
+        list_failures(failures)
+    ))]
+    NoAccess {
+        failures: Vec<(ECAccessMode, ProbeFailure)>,
+    },
+}
+
+/// Open the device at `path` with `mode` and check that it can be used,
+/// by reading the first register and checking the write permission.
+fn probe(
+    mode: ECAccessMode,
+    path: &Path,
+    devices: &ECDevices,
+) -> Result<Box<dyn RW>, ProbeFailure> {
+    let permissions = match metadata(path) {
+        Ok(m) => m.permissions(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Missing { path }.fail(),
+        Err(e) => return Err(e).context(Open { path }),
+    };
+    // The permissions are checked since root can open a read-only file for writing,
+    // e.g. the file of `ec_sys` without `write_support=1`.
+    if permissions.mode() & 0o222 == 0 {
+        return ReadOnly { path }.fail();
+    }
+
+    let dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context(Open { path })?;
+    // We have to "wrap" /dev/port to access to the EC.
+    let mut dev = if mode == ECAccessMode::RawPort {
+        Box::from(RawPort::with_ports(
+            dev,
+            devices.command_port,
+            devices.data_port,
+            devices.port_wait(),
+        )) as Box<dyn RW>
+    } else {
+        Box::from(dev) as Box<dyn RW>
+    };
+
+    let mut buf = [0u8];
+    dev.seek(SeekFrom::Start(0))
+        .and_then(|_| dev.read_exact(&mut buf))
+        .context(ReadTest { path })?;
+    Ok(dev)
+}
+
+/// Open the EC with `mode` and the `devices`,
+/// or with the first access mode which works if it is `Either`.
+pub(crate) fn open_ec(
+    mode: ECAccessMode,
+    devices: &ECDevices,
+) -> Result<(Box<dyn RW>, ECAccessMode), ProbeError> {
+    let mut failures = Vec::new();
+    for &mode in mode.candidates() {
+        let path = match mode.to_path(devices) {
+            Some(path) => path,
+            None => continue,
+        };
+        match probe(mode, path, devices) {
+            Ok(dev) => {
+                info!("Accessing to the EC with {:?} (`{}`)", mode, path.display());
+                return Ok((dev, mode));
+            }
+            Err(e) => {
+                warn!("The EC cannot be accessed with {:?}: {}", mode, e);
+                failures.push((mode, e));
+            }
+        }
+    }
+
+    NoAccess { failures }.fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions};
+
+    #[test]
+    fn candidates() {
+        let dir = std::env::temp_dir().join(format!("fancy-probe-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join("ec"), "").unwrap();
+        write(dir.join("io"), [0x2a; 256]).unwrap();
+        set_permissions(dir.join("io"), Permissions::from_mode(0o400)).unwrap();
+
+        let devices = ECDevices {
+            raw_port: dir.join("port"),
+            acpi_ec: dir.join("ec"),
+            ec_sys: dir.join("io"),
+            ..Default::default()
+        };
+
+        let err = open_ec(ECAccessMode::Either, &devices).err().unwrap();
+        let ProbeError::NoAccess { failures } = &err;
+        assert!(matches!(
+            failures.as_slice(),
+            [
+                (ECAccessMode::RawPort, ProbeFailure::Missing { .. }),
+                (ECAccessMode::AcpiEC, ProbeFailure::ReadTest { .. }),
+                (ECAccessMode::ECSys, ProbeFailure::ReadOnly { .. }),
+            ]
+        ));
+        assert!(err.to_string().contains("ECSys: "));
+
+        // The first candidate which works is used.
+        set_permissions(dir.join("io"), Permissions::from_mode(0o600)).unwrap();
+        let (mut dev, mode) = open_ec(ECAccessMode::Either, &devices).unwrap();
+        assert_eq!(mode, ECAccessMode::ECSys);
+        assert!(open_ec(ECAccessMode::AcpiEC, &devices).is_err());
+        let mut buf = [0u8];
+        dev.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 0x2a);
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
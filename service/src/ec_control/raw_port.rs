@@ -1,9 +1,13 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use snafu::{ResultExt, Snafu};
+
 use super::RW;
 
-use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, PartialEq)]
 enum BufferEmpty {
@@ -14,41 +18,145 @@ enum BufferEmpty {
 const EC_COMMAND_READ: u8 = 0x80;
 const EC_COMMAND_WRITE: u8 = 0x81;
 
-const RAW_PORT_TIMEOUT: u16 = 2000;
+/// Command (and status) port of the first EC.
+pub(crate) const DEFAULT_COMMAND_PORT: u16 = 0x66;
+/// Data port of the first EC.
+pub(crate) const DEFAULT_DATA_PORT: u16 = 0x62;
 
-const COMMAND_PORT: SeekFrom = SeekFrom::Start(0x66);
-const DATA_PORT: SeekFrom = SeekFrom::Start(0x62);
+/// Time after which a wait for the EC fails by default.
+pub(crate) const DEFAULT_PORT_TIMEOUT: Duration = Duration::from_millis(100);
 
 type Result<T = ()> = std::result::Result<T, Error>;
 
+#[derive(Debug, Snafu)]
+/// Describe why an access to the EC through its ports failed.
+pub(crate) enum PortError {
+    #[snafu(display(
+        "The EC did not read its input buffer (IBF set on port {:#x}) within {:?}",
+        port,
+        timeout
+    ))]
+    InputBufferFull { port: u16, timeout: Duration },
+
+    #[snafu(display(
+        "The EC did not fill its output buffer (OBF clear on port {:#x}) within {:?}",
+        port,
+        timeout
+    ))]
+    OutputBufferEmpty { port: u16, timeout: Duration },
+
+    #[snafu(display("An I/O error occured with the port {:#x}: {}", port, source))]
+    PortIO { port: u16, source: std::io::Error },
+}
+
+impl PortError {
+    /// Get the `PortError` held by `e`, if it comes from a `RawPort`.
+    pub fn from_io(e: Error) -> std::result::Result<Self, Error> {
+        e.downcast::<PortError>()
+    }
+}
+
+impl From<PortError> for Error {
+    fn from(e: PortError) -> Self {
+        let kind = match &e {
+            PortError::InputBufferFull { .. } | PortError::OutputBufferEmpty { .. } => {
+                ErrorKind::TimedOut
+            }
+            PortError::PortIO { source, .. } => source.kind(),
+        };
+        Error::new(kind, e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// Describe how to wait for the EC.
+pub(crate) struct PortWait {
+    /// Time after which the wait fails.
+    pub timeout: Duration,
+    /// Sleep between two checks of the status, doubled after each check up to `max_sleep`.
+    /// The status is checked continuously if it is zero.
+    pub sleep: Duration,
+    pub max_sleep: Duration,
+}
+
+impl PortWait {
+    /// Get the sleep which follows `sleep` between two checks of the status.
+    fn next_sleep(&self, sleep: Duration) -> Duration {
+        (sleep * 2).min(self.max_sleep.max(self.sleep))
+    }
+}
+
+impl Default for PortWait {
+    fn default() -> Self {
+        PortWait {
+            timeout: DEFAULT_PORT_TIMEOUT,
+            sleep: Duration::ZERO,
+            max_sleep: Duration::ZERO,
+        }
+    }
+}
+
 /// Wraps reads and writes to `/dev/port`.
 /// `/dev/port` is mapped to I/O ports, and thus we need an abstraction layer for reading/writing from/to the EC.
 #[derive(Debug)]
 pub(crate) struct RawPort<T: RW> {
     inner: T,
     pos: u8,
+    command_port: u16,
+    data_port: u16,
+    wait: PortWait,
 }
 
 impl<T: RW> From<T> for RawPort<T> {
     fn from(inner: T) -> Self {
-        RawPort { inner, pos: 0 }
+        RawPort::with_ports(
+            inner,
+            DEFAULT_COMMAND_PORT,
+            DEFAULT_DATA_PORT,
+            PortWait::default(),
+        )
     }
 }
 
 impl<T: RW> RawPort<T> {
+    /// Wrap `inner` to access to the EC with the ports `command_port` and `data_port`
+    /// (e.g. for a second EC), waiting for it as described by `wait`.
+    pub fn with_ports(inner: T, command_port: u16, data_port: u16, wait: PortWait) -> Self {
+        RawPort {
+            inner,
+            pos: 0,
+            command_port,
+            data_port,
+            wait,
+        }
+    }
+
+    /// Seek to `port` and read a byte.
+    fn read_port(&mut self, port: u16) -> std::result::Result<u8, PortError> {
+        let mut value = [0u8; 1];
+        self.inner
+            .seek(SeekFrom::Start(port.into()))
+            .and_then(|_| self.inner.read_exact(&mut value))
+            .context(PortIO { port })?;
+        Ok(value[0])
+    }
+
+    /// Seek to `port` and write `byte`.
+    fn write_port(&mut self, port: u16, byte: u8) -> std::result::Result<(), PortError> {
+        self.inner
+            .seek(SeekFrom::Start(port.into()))
+            .and_then(|_| self.inner.write_all(&[byte]))
+            .context(PortIO { port })
+    }
+
     /// Low-level wait function before reading/writing to `/dev/port`.
     ///
     /// It waits for input/output buffer to be empty and return an error on timeout.
-    fn raw_port_wait(&mut self, buffer_type: BufferEmpty) -> Result {
-        let mut retries = RAW_PORT_TIMEOUT;
-        while retries > 0 {
-            retries -= 1;
-
-            let mut value = [0u8; 1];
-            self.inner.seek(COMMAND_PORT)?;
-            self.inner.read_exact(&mut value)?;
-
-            let mut value = value[0];
+    fn raw_port_wait(&mut self, buffer_type: BufferEmpty) -> std::result::Result<(), PortError> {
+        let deadline = Instant::now() + self.wait.timeout;
+        let mut delay = self.wait.sleep;
+        loop {
+            let mut value = self.read_port(self.command_port)?;
             // Invert the value for output buffer.
             if buffer_type == BufferEmpty::OutputBuffer {
                 value = !value;
@@ -57,49 +165,55 @@ impl<T: RW> RawPort<T> {
             if (value & (buffer_type as u8)) == 0 {
                 return Ok(());
             }
-        }
 
-        Err(Error::from(std::io::ErrorKind::TimedOut))
+            let now = Instant::now();
+            if now >= deadline {
+                let (port, timeout) = (self.command_port, self.wait.timeout);
+                return match buffer_type {
+                    BufferEmpty::InputBuffer => InputBufferFull { port, timeout }.fail(),
+                    BufferEmpty::OutputBuffer => OutputBufferEmpty { port, timeout }.fail(),
+                };
+            }
+            if !delay.is_zero() {
+                sleep(delay.min(deadline - now));
+                delay = self.wait.next_sleep(delay);
+            }
+        }
     }
 
     /// Read variant of [`raw_port_wait`](#method.raw_port_wait).
-    fn raw_port_wait_read(&mut self) -> Result {
+    fn raw_port_wait_read(&mut self) -> std::result::Result<(), PortError> {
         self.raw_port_wait(BufferEmpty::OutputBuffer)
     }
 
     /// Write variant of [`raw_port_wait`](#method.raw_port_wait).
-    fn raw_port_wait_write(&mut self) -> Result {
+    fn raw_port_wait_write(&mut self) -> std::result::Result<(), PortError> {
         self.raw_port_wait(BufferEmpty::InputBuffer)
     }
 
     /// Write data (a query) to a port.
-    fn raw_port_query(&mut self, port: SeekFrom, query: u8) -> Result {
+    fn raw_port_query(&mut self, port: u16, query: u8) -> std::result::Result<(), PortError> {
         self.raw_port_wait_write()?;
-        self.inner.seek(port)?;
-        self.inner.write_all(&[query])
+        self.write_port(port, query)
     }
 
     /// Read a byte from the EC at `offset`.
     fn ec_read_byte(&mut self, offset: u8) -> Result<u8> {
-        self.raw_port_query(COMMAND_PORT, EC_COMMAND_READ)?;
-        self.raw_port_query(DATA_PORT, offset)?;
+        self.raw_port_query(self.command_port, EC_COMMAND_READ)?;
+        self.raw_port_query(self.data_port, offset)?;
 
         self.raw_port_wait_read()?;
         self.raw_port_wait_write()?;
 
-        let mut byte = [0u8; 1];
-        self.inner.seek(DATA_PORT)?;
-        self.inner.read_exact(&mut byte)?;
-
-        Ok(byte[0])
+        Ok(self.read_port(self.data_port)?)
     }
 
     /// Write a byte to the EC at `offset`.
     fn ec_write_byte(&mut self, offset: u8, byte: u8) -> Result {
-        self.raw_port_query(COMMAND_PORT, EC_COMMAND_WRITE)?;
-        self.raw_port_query(DATA_PORT, offset)?;
+        self.raw_port_query(self.command_port, EC_COMMAND_WRITE)?;
+        self.raw_port_query(self.data_port, offset)?;
 
-        self.raw_port_query(DATA_PORT, byte)
+        Ok(self.raw_port_query(self.data_port, byte)?)
     }
 }
 
@@ -153,8 +267,8 @@ mod tests {
 
     use super::*;
 
-    const COMMAND_PORT_UINT: u8 = 0x66;
-    const DATA_PORT_UINT: u8 = 0x62;
+    const COMMAND_PORT_UINT: u8 = DEFAULT_COMMAND_PORT as u8;
+    const DATA_PORT_UINT: u8 = DEFAULT_DATA_PORT as u8;
 
     /// Emulates EC access through `/dev/port`.
     #[derive(Debug)]
@@ -165,18 +279,24 @@ mod tests {
         pub register: Option<u8>,
         pub full_input: bool,
         pub full_output: bool,
+        pub failing_reads: bool,
+        pub command_port: u8,
+        pub data_port: u8,
         pos: u8,
     }
 
     impl BufferTest {
         fn new() -> BufferTest {
             BufferTest {
+                command_port: COMMAND_PORT_UINT,
+                data_port: DATA_PORT_UINT,
                 writes: Vec::new(),
                 reads: Vec::new(),
                 registers: HashMap::new(),
                 register: None,
                 full_input: false,
                 full_output: false,
+                failing_reads: false,
                 pos: 0,
             }
         }
@@ -185,7 +305,7 @@ mod tests {
     impl Write for BufferTest {
         fn write(&mut self, buf: &[u8]) -> Result<usize> {
             assert!(buf.len() == 1);
-            if self.pos == DATA_PORT_UINT && self.register.is_none() {
+            if self.pos == self.data_port && self.register.is_none() {
                 self.register = Some(buf[0]);
             } else if let Some(register) = self.register {
                 self.registers.insert(register, buf[0]);
@@ -203,14 +323,18 @@ mod tests {
     impl Read for BufferTest {
         fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
             assert!(buf.len() == 1);
-            buf[0] = if self.pos == COMMAND_PORT_UINT {
+            if self.failing_reads {
+                return Err(Error::from(ErrorKind::PermissionDenied));
+            }
+            buf[0] = if self.pos == self.command_port {
+                // Only the bit of each buffer is set, so they can be tested separately.
                 let input_status = if self.full_input {
                     BufferEmpty::InputBuffer as u8
                 } else {
-                    !(BufferEmpty::InputBuffer as u8)
+                    0
                 };
                 let output_status = if self.full_output {
-                    !(BufferEmpty::OutputBuffer as u8)
+                    0
                 } else {
                     BufferEmpty::OutputBuffer as u8
                 };
@@ -219,7 +343,10 @@ mod tests {
             } else if let Some(register) = self.register {
                 *self.registers.get(&register).unwrap()
             } else {
-                unreachable!()
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "No register has been queried",
+                ));
             };
             self.reads.push((self.pos, buf[0]));
             Ok(1)
@@ -233,23 +360,44 @@ mod tests {
                 return Ok(self.pos as u64);
             }
 
-            unreachable!()
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only absolute positions are supported",
+            ))
         }
     }
 
+    /// Wrap `buffer` with the default ports and a short timeout.
+    fn short_wait(buffer: &mut BufferTest, wait: PortWait) -> RawPort<&mut BufferTest> {
+        RawPort::with_ports(buffer, DEFAULT_COMMAND_PORT, DEFAULT_DATA_PORT, wait)
+    }
+
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(10);
+
     #[test]
     fn wait_before_write() {
         let mut buffer = BufferTest::new();
         buffer.full_input = true;
 
-        let mut raw_port = RawPort::from(&mut buffer);
-
-        let result = raw_port.write(&[0]);
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
-
-        assert_eq!(buffer.reads.len(), RAW_PORT_TIMEOUT as usize);
-        assert!(buffer.reads.iter().all(|e| e.0 == COMMAND_PORT_UINT as u8));
+        let start = Instant::now();
+        let wait = PortWait {
+            timeout: SHORT_TIMEOUT,
+            ..Default::default()
+        };
+        let result = short_wait(&mut buffer, wait).write(&[0]);
+        assert!(start.elapsed() >= SHORT_TIMEOUT);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(matches!(
+            PortError::from_io(err),
+            Ok(PortError::InputBufferFull {
+                port: DEFAULT_COMMAND_PORT,
+                timeout: SHORT_TIMEOUT,
+            })
+        ));
+
+        assert!(!buffer.reads.is_empty());
+        assert!(buffer.reads.iter().all(|e| e.0 == COMMAND_PORT_UINT));
     }
 
     #[test]
@@ -257,14 +405,77 @@ mod tests {
         let mut buffer = BufferTest::new();
         buffer.full_output = true;
 
-        let mut raw_port = RawPort::from(&mut buffer);
+        let wait = PortWait {
+            timeout: SHORT_TIMEOUT,
+            ..Default::default()
+        };
+        let err = short_wait(&mut buffer, wait).read(&mut [0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(matches!(
+            PortError::from_io(err),
+            Ok(PortError::OutputBufferEmpty { .. })
+        ));
+
+        // The query has been written before waiting for the output buffer.
+        assert_eq!(buffer.writes.len(), 2);
+        assert!(buffer.reads.iter().all(|e| e.0 == COMMAND_PORT_UINT));
+    }
 
-        let result = raw_port.read(&mut [0]);
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    #[test]
+    fn wait_with_backoff() {
+        let mut buffer = BufferTest::new();
+        buffer.full_input = true;
 
-        assert_eq!(buffer.reads.len(), RAW_PORT_TIMEOUT as usize);
-        assert!(buffer.reads.iter().all(|e| e.0 == COMMAND_PORT_UINT as u8));
+        let wait = PortWait {
+            timeout: Duration::from_millis(30),
+            sleep: Duration::from_millis(1),
+            max_sleep: Duration::from_millis(4),
+        };
+        let start = Instant::now();
+        let err = short_wait(&mut buffer, wait).write(&[0]).unwrap_err();
+        assert!(start.elapsed() >= wait.timeout);
+        assert!(matches!(
+            PortError::from_io(err),
+            Ok(PortError::InputBufferFull { .. })
+        ));
+
+        // 1 + 2 + 4 + 4... milliseconds between the checks.
+        let sleeps: Vec<u64> =
+            std::iter::successors(Some(wait.sleep), |&s| Some(wait.next_sleep(s)))
+                .take(5)
+                .map(|s| s.as_millis() as u64)
+                .collect();
+        assert_eq!(sleeps, vec![1, 2, 4, 4, 4]);
+        // The sleep does not decrease when `max_sleep` is lower.
+        let wait = PortWait {
+            max_sleep: Duration::ZERO,
+            ..wait
+        };
+        assert_eq!(wait.next_sleep(wait.sleep), wait.sleep);
+    }
+
+    #[test]
+    fn port_io_error() {
+        let mut buffer = BufferTest::new();
+        buffer.failing_reads = true;
+
+        let err = RawPort::from(&mut buffer).read(&mut [0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(matches!(
+            PortError::from_io(err),
+            Ok(PortError::PortIO {
+                port: DEFAULT_COMMAND_PORT,
+                ..
+            })
+        ));
+        assert!(buffer.writes.is_empty());
+
+        let err = Error::from(ErrorKind::TimedOut);
+        assert!(PortError::from_io(err).is_err());
+        // The other errors are given back unchanged.
+        let err = PortError::from_io(Error::other("not a port")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(err.to_string(), "not a port");
     }
 
     #[test]
@@ -328,4 +539,24 @@ mod tests {
             (DATA_PORT_UINT, excepted_value)
         );
     }
+
+    #[test]
+    fn custom_ports() {
+        let mut buffer = BufferTest::new();
+        buffer.command_port = 0x6c;
+        buffer.data_port = 0x68;
+        buffer.registers.insert(0x2f, 42);
+
+        let mut raw_port = RawPort::with_ports(&mut buffer, 0x6c, 0x68, PortWait::default());
+        raw_port.seek(SeekFrom::Start(0x2f)).unwrap();
+        let mut value = [0u8; 1];
+        raw_port.read_exact(&mut value).unwrap();
+        assert_eq!(value[0], 42);
+        raw_port.seek(SeekFrom::Start(0x30)).unwrap();
+        raw_port.write_all(&[100]).unwrap();
+
+        assert_eq!(*buffer.registers.get(&0x30).unwrap(), 100);
+        assert!(buffer.writes.iter().all(|e| e.0 == 0x6c || e.0 == 0x68));
+        assert!(buffer.reads.iter().all(|e| e.0 == 0x6c || e.0 == 0x68));
+    }
 }
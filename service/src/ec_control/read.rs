@@ -90,6 +90,17 @@ impl<R: Read + Seek> ECReader<R> {
         Ok(percentage.clamp(0.0, 100.0))
     }
 
+    /// Read the byte at `register`, regardless of the size of the values of the fans.
+    pub fn read_register(&self, register: u8) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        let mut dev = (*self.ec_dev).borrow_mut();
+
+        dev.seek(SeekFrom::Start(register.into()))?;
+        dev.read_exact(&mut buf)?;
+
+        Ok(buf[0])
+    }
+
     /// Low-level read function.
     // XXX: The function returns an u16 even if just a u8 is needed
     fn read_value(&self, read_off: SeekFrom) -> Result<u16> {
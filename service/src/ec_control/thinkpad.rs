@@ -0,0 +1,382 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::{debug, info};
+
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use super::backend::FanBackend;
+use crate::nbfc::{FanConfiguration, FanControlConfigV2, TemperatureThreshold};
+
+type Result<T = ()> = std::result::Result<T, Error>;
+
+/// Highest manual level of the fan.
+const MAX_LEVEL: u8 = 7;
+/// Default delay (in seconds) after which `thinkpad_acpi` gives the control back to the firmware
+/// if no command has been written.
+const DEFAULT_WATCHDOG: u32 = 120;
+/// Poll interval (in milliseconds) of the fan control configuration provided by the backend.
+const POLL_INTERVAL: u64 = 1000;
+/// Critical temperature of the fan control configuration provided by the backend.
+const CRITICAL_TEMPERATURE: u8 = 90;
+
+/// Get the thresholds used when none are configured, which go through the levels up to the
+/// full speed.
+fn default_thresholds() -> Vec<TemperatureThreshold> {
+    [
+        (0, 0, 0.0),
+        (55, 45, 25.0),
+        (65, 55, 50.0),
+        (75, 65, 75.0),
+        (85, 75, 100.0),
+    ]
+    .into_iter()
+    .map(
+        |(up_threshold, down_threshold, fan_speed)| TemperatureThreshold {
+            up_threshold,
+            down_threshold,
+            fan_speed,
+        },
+    )
+    .collect()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Describe a level of the fan of `/proc/acpi/ibm/fan`.
+enum FanLevel {
+    /// Manual level, between 0 and `MAX_LEVEL`.
+    Manual(u8),
+    /// The firmware controls the fan.
+    Auto,
+    /// The fan runs at its maximum speed, without regulation (`disengaged` or `full-speed`).
+    FullSpeed,
+}
+
+impl fmt::Display for FanLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FanLevel::Manual(level) => write!(f, "{}", level),
+            FanLevel::Auto => f.write_str("auto"),
+            FanLevel::FullSpeed => f.write_str("full-speed"),
+        }
+    }
+}
+
+impl FanLevel {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(FanLevel::Auto),
+            "disengaged" | "full-speed" => Some(FanLevel::FullSpeed),
+            level => level
+                .parse()
+                .ok()
+                .filter(|&l| l <= MAX_LEVEL)
+                .map(FanLevel::Manual),
+        }
+    }
+
+    /// Get the lowest level which reaches the speed `percent`.
+    fn from_percent(percent: f64) -> Self {
+        let level = (percent.clamp(0.0, 100.0) / 100.0 * f64::from(MAX_LEVEL)).ceil();
+        FanLevel::Manual(level as u8)
+    }
+
+    /// Get the speed of the level in percent, if it is known.
+    fn percent(&self) -> Option<f64> {
+        match self {
+            FanLevel::Manual(level) => Some(f64::from(*level) / f64::from(MAX_LEVEL) * 100.0),
+            FanLevel::Auto => None,
+            FanLevel::FullSpeed => Some(100.0),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// Holds the values read from `/proc/acpi/ibm/fan`.
+struct FanStatus {
+    /// Speed in RPM.
+    speed: Option<u32>,
+    level: Option<FanLevel>,
+}
+
+impl FanStatus {
+    fn parse(content: &str) -> Self {
+        let mut status = FanStatus::default();
+        for (key, value) in content.lines().filter_map(|l| l.split_once(':')) {
+            match key.trim() {
+                "speed" => status.speed = value.trim().parse().ok(),
+                "level" => status.level = FanLevel::parse(value.trim()),
+                _ => {}
+            }
+        }
+        status
+    }
+}
+
+#[derive(Debug)]
+/// Controls the fan of the ThinkPads with the levels of `/proc/acpi/ibm/fan`,
+/// which requires `thinkpad_acpi` to be loaded with `fan_control=1`.
+/// Every fan of the fan control configuration follows the same level.
+pub(crate) struct ThinkpadBackend {
+    path: PathBuf,
+    /// Delay (in seconds) after which the firmware takes the control back if no command is written.
+    watchdog: u32,
+    /// Speed of the fan (in RPM) at full speed, used to read its speed.
+    max_rpm: Option<u32>,
+    /// Thresholds of the fan control configuration provided by the backend.
+    thresholds: Vec<TemperatureThreshold>,
+    /// Last speed written for each fan.
+    speeds: Vec<Option<f64>>,
+    /// Last level written.
+    level: Option<FanLevel>,
+}
+
+impl ThinkpadBackend {
+    /// Create a backend for the fan file at `path` (usually `/proc/acpi/ibm/fan`).
+    /// The default thresholds are used for the fan when `thresholds` is `None`.
+    pub fn new<P: Into<PathBuf>>(
+        path: P,
+        watchdog: Option<u32>,
+        max_rpm: Option<u32>,
+        thresholds: Option<Vec<TemperatureThreshold>>,
+    ) -> Self {
+        ThinkpadBackend {
+            path: path.into(),
+            watchdog: watchdog.unwrap_or(DEFAULT_WATCHDOG),
+            max_rpm,
+            thresholds: thresholds.unwrap_or_else(default_thresholds),
+            speeds: Vec::new(),
+            level: None,
+        }
+    }
+
+    fn command(&self, command: &str) -> Result {
+        debug!("Writing `{}` to `{}`", command, self.path.display());
+        write(&self.path, command)
+    }
+
+    fn status(&self) -> Result<FanStatus> {
+        read_to_string(&self.path).map(|c| FanStatus::parse(&c))
+    }
+}
+
+impl FanBackend for ThinkpadBackend {
+    fn refresh_config(&mut self, c: &FanControlConfigV2) -> Result {
+        // The level is only written at each poll, so the watchdog must not expire in between.
+        if u64::from(self.watchdog) * 1000 <= c.ec_poll_interval {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The watchdog ({}s) must be longer than the poll interval ({}ms)",
+                    self.watchdog, c.ec_poll_interval
+                ),
+            ));
+        }
+
+        self.speeds = vec![None; c.fan_configurations.len()];
+        self.level = None;
+        info!(
+            "Controlling the fan with `{}` (watchdog: {}s)",
+            self.path.display(),
+            self.watchdog
+        );
+
+        self.init()
+    }
+
+    fn init(&mut self) -> Result {
+        self.command(&format!("watchdog {}", self.watchdog))
+    }
+
+    fn reset(&mut self, _reset_all: bool) -> Result {
+        self.level = None;
+        self.command(&format!("level {}", FanLevel::Auto))?;
+        self.command("watchdog 0")
+    }
+
+    fn write_speed_percent(&mut self, fan_index: usize, speed_percent: f64) -> Result {
+        let speed = self.speeds.get_mut(fan_index).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("There is no fan with index {}", fan_index),
+            )
+        })?;
+        *speed = Some(speed_percent);
+
+        // The fans share the level, so the fastest speed is used.
+        let percent = self
+            .speeds
+            .iter()
+            .flatten()
+            .fold(0.0, |m: f64, &s| m.max(s));
+        let level = FanLevel::from_percent(percent);
+        self.command(&format!("level {}", level))?;
+        self.level = Some(level);
+        Ok(())
+    }
+
+    fn read_speed_percent(&self, _fan_index: usize) -> Result<f64> {
+        let status = self.status()?;
+        let percent = match (self.max_rpm.filter(|&m| m > 0), status.speed) {
+            (Some(max_rpm), Some(speed)) => f64::from(speed) / f64::from(max_rpm) * 100.0,
+            _ => status.level.and_then(|l| l.percent()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Could not read the speed from `{}`", self.path.display()),
+                )
+            })?,
+        };
+        Ok(percent.clamp(0.0, 100.0))
+    }
+
+    fn verify_init(&self) -> Result<bool> {
+        // The level is `auto` until the first speed has been written.
+        Ok(self.level.is_none() || self.status()?.level != Some(FanLevel::Auto))
+    }
+
+    fn verify_speed(&self, _fan_index: usize) -> Result<bool> {
+        match self.level {
+            Some(level) => Ok(self.status()?.level == Some(level)),
+            None => Ok(true),
+        }
+    }
+
+    fn default_config(&self) -> Option<FanControlConfigV2> {
+        // The registers and speed values are not used by the backend.
+        let fan = FanConfiguration {
+            read_register: 0,
+            write_register: 0,
+            min_speed_value: 0,
+            max_speed_value: u16::from(MAX_LEVEL),
+            independent_read_min_max_values: false,
+            min_speed_value_read: 0,
+            max_speed_value_read: 0,
+            reset_required: false,
+            fan_speed_reset_value: None,
+            fan_display_name: Some("Fan".to_owned()),
+            temperature_thresholds: self.thresholds.clone(),
+            fan_speed_percentage_overrides: None,
+        };
+        Some(FanControlConfigV2 {
+            notebook_model: "ThinkPad".to_owned(),
+            author: None,
+            ec_poll_interval: POLL_INTERVAL,
+            read_write_words: false,
+            critical_temperature: CRITICAL_TEMPERATURE,
+            fan_configurations: vec![fan],
+            register_write_configurations: None,
+        })
+    }
+
+    fn keep_alive(&mut self) -> Result {
+        // Any command resets the watchdog.
+        match self.level {
+            Some(level) => self.command(&format!("level {}", level)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ec_control::backend::fans_config as config;
+    use std::fs::remove_file;
+
+    const STATUS: &str = "status:\t\tenabled
+speed:\t\t2700
+level:\t\t3
+commands:\tlevel <level> (<level> is 0-7, auto, disengaged, full-speed)
+commands:\tenable, disable
+commands:\twatchdog <timeout> (<timeout> is 0 (off), 1-120 (seconds))
+";
+
+    #[test]
+    fn levels() {
+        assert_eq!(FanLevel::from_percent(0.0), FanLevel::Manual(0));
+        assert_eq!(FanLevel::from_percent(10.0), FanLevel::Manual(1));
+        assert_eq!(FanLevel::from_percent(50.0), FanLevel::Manual(4));
+        assert_eq!(FanLevel::from_percent(100.0), FanLevel::Manual(7));
+        assert_eq!(FanLevel::from_percent(150.0), FanLevel::Manual(7));
+        assert_eq!(FanLevel::from_percent(f64::NAN), FanLevel::Manual(0));
+
+        assert_eq!(FanLevel::parse("disengaged"), Some(FanLevel::FullSpeed));
+        assert_eq!(FanLevel::parse("8"), None);
+        assert_eq!(
+            FanStatus::parse(STATUS),
+            FanStatus {
+                speed: Some(2700),
+                level: Some(FanLevel::Manual(3)),
+            }
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let path = std::env::temp_dir().join(format!("fancy-ibm-fan-{}", std::process::id()));
+        let read = || read_to_string(&path).unwrap();
+
+        let mut backend = ThinkpadBackend::new(&path, Some(30), None, None);
+        backend.refresh_config(&config(2)).unwrap();
+        assert_eq!(read(), "watchdog 30");
+        assert!(backend.verify_init().unwrap());
+
+        backend.write_speed_percent(0, 60.0).unwrap();
+        assert_eq!(read(), "level 5");
+        backend.write_speed_percent(1, 20.0).unwrap();
+        assert_eq!(read(), "level 5");
+        backend.write_speed_percent(0, 0.0).unwrap();
+        assert_eq!(read(), "level 2");
+
+        write(&path, "").unwrap();
+        backend.keep_alive().unwrap();
+        assert_eq!(read(), "level 2");
+
+        // The status written by the driver.
+        write(&path, STATUS).unwrap();
+        assert!(backend.verify_init().unwrap());
+        assert!(!backend.verify_speed(0).unwrap());
+        assert!((backend.read_speed_percent(0).unwrap() - 42.86).abs() < 0.01);
+        write(&path, STATUS.replace("level:\t\t3", "level:\t\tauto")).unwrap();
+        assert!(!backend.verify_init().unwrap());
+        assert!(backend.read_speed_percent(0).is_err());
+
+        let backend = ThinkpadBackend::new(&path, None, Some(5400), None);
+        assert_eq!(backend.read_speed_percent(0).unwrap(), 50.0);
+
+        let mut backend = ThinkpadBackend::new(&path, None, None, None);
+        backend.refresh_config(&config(1)).unwrap();
+        assert_eq!(read(), "watchdog 120");
+        backend.reset(true).unwrap();
+        assert_eq!(read(), "watchdog 0");
+        assert!(backend.write_speed_percent(1, 50.0).is_err());
+
+        remove_file(&path).unwrap();
+    }
+    #[test]
+    fn default_config() {
+        let path = std::env::temp_dir().join(format!("fancy-ibm-fan-{}", std::process::id()));
+
+        let mut backend = ThinkpadBackend::new(&path, Some(1), None, None);
+        let c = backend.default_config().unwrap();
+        assert_eq!(c.fan_configurations.len(), 1);
+        assert!(crate::nbfc::check_control_config(&c).is_ok());
+        // The watchdog would expire between two polls.
+        assert!(backend.refresh_config(&c).is_err());
+
+        let thresholds = vec![TemperatureThreshold {
+            up_threshold: 0,
+            down_threshold: 0,
+            fan_speed: 100.0,
+        }];
+        let mut backend = ThinkpadBackend::new(&path, None, None, Some(thresholds.clone()));
+        let c = backend.default_config().unwrap();
+        assert_eq!(c.fan_configurations[0].temperature_thresholds, thresholds);
+        backend.refresh_config(&c).unwrap();
+
+        remove_file(&path).unwrap();
+    }
+}
@@ -3,7 +3,8 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use log::debug;
 
-use std::io::{Error, Seek, SeekFrom, Write};
+use std::collections::HashMap;
+use std::io::{Error, Read, Seek, SeekFrom, Write};
 
 use super::RcWrapper;
 use crate::nbfc::*;
@@ -17,6 +18,8 @@ struct FanWriteConfig {
     max_speed: u16,
     min_speed: u16,
     write_percent_overrides: Option<Vec<FanSpeedPercentageOverride>>,
+    /// Last value written to the register.
+    written_value: Option<u16>,
 }
 
 #[derive(Debug)]
@@ -84,6 +87,7 @@ impl<W: Write + Seek> ECWriter<W> {
                         .cloned()
                         .collect()
                 }),
+                written_value: None,
             })
             .collect();
 
@@ -91,7 +95,7 @@ impl<W: Write + Seek> ECWriter<W> {
     }
 
     /// Function to call before starting to write. It initialize the EC controller so it can be used.
-    fn init_write(&mut self) -> Result {
+    pub fn init_write(&mut self) -> Result {
         if let Some(reg_confs) = &self.init_reg_confs {
             for reg_conf in reg_confs.iter() {
                 let write_off = SeekFrom::Start(reg_conf.register as u64);
@@ -170,7 +174,14 @@ impl<W: Write + Seek> ECWriter<W> {
         };
 
         let write_off = SeekFrom::Start(fan.write_register as u64);
-        self.write_value(self.write_words, write_off, &speed)
+        self.write_value(self.write_words, write_off, &speed)?;
+
+        self.fans_write_config[fan_index].written_value = Some(if self.write_words {
+            u16::from_le_bytes(speed)
+        } else {
+            speed[0].into()
+        });
+        Ok(())
     }
 
     /// Low-level write function.
@@ -188,6 +199,74 @@ impl<W: Write + Seek> ECWriter<W> {
     }
 }
 
+impl<W: Read + Write + Seek> ECWriter<W> {
+    /// Check if the registers written during the initialization still hold their values.
+    /// The registers which are also written when the speed is written are not checked.
+    pub fn verify_init(&self) -> Result<bool> {
+        let mut overwritten_registers: Vec<u8> = self
+            .on_write_reg_confs
+            .iter()
+            .flatten()
+            .map(|r| r.register)
+            .collect();
+        for c in &self.fans_write_config {
+            overwritten_registers.push(c.write_register);
+            if self.write_words {
+                overwritten_registers.push(c.write_register.wrapping_add(1));
+            }
+        }
+
+        // The last value written to a register is the one which should be there.
+        let expected_values: HashMap<u8, u8> = self
+            .init_reg_confs
+            .iter()
+            .flatten()
+            .filter(|r| !overwritten_registers.contains(&r.register))
+            .map(|r| (r.register, r.value))
+            .collect();
+
+        for (&register, &expected_value) in &expected_values {
+            let value = self.read_value(false, SeekFrom::Start(register as u64))?;
+            if value != expected_value as u16 {
+                debug!(
+                    "Register {} holds {} instead of {}",
+                    register, value, expected_value
+                );
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Check if the register of the fan specified by `fan_index` still holds the last written speed.
+    pub fn verify_speed(&self, fan_index: usize) -> Result<bool> {
+        let fan = &self.fans_write_config[fan_index];
+        match fan.written_value {
+            Some(written_value) => {
+                let read_off = SeekFrom::Start(fan.write_register as u64);
+                Ok(self.read_value(self.write_words, read_off)? == written_value)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Low-level function to read back a written value.
+    fn read_value(&self, read_word: bool, read_off: SeekFrom) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        let mut dev = (*self.ec_dev).borrow_mut();
+
+        dev.seek(read_off)?;
+        dev.read_exact(if read_word {
+            &mut buf[..]
+        } else {
+            &mut buf[..=0]
+        })?;
+
+        Ok(u16::from_le_bytes(buf))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -580,4 +659,69 @@ mod tests {
             }
         });
     }
+
+    #[test]
+    fn verify_writes() {
+        CONFIGS_PARSED.iter().for_each(|c| {
+            let ec = Cursor::new(vec![0; 256]);
+            let ec = Rc::new(RefCell::new(ec));
+            let mut writer = ECWriter::new(Rc::clone(&ec));
+            writer
+                .refresh_config(
+                    c.read_write_words,
+                    c.register_write_configurations.clone(),
+                    &c.fan_configurations,
+                )
+                .unwrap();
+
+            assert!(writer.verify_init().unwrap());
+
+            for i in 0..c.fan_configurations.len() {
+                assert!(writer.verify_speed(i).unwrap());
+                writer.write_speed_percent(i, 50.0).unwrap();
+                assert!(writer.verify_speed(i).unwrap());
+
+                let write_off = c.fan_configurations[i].write_register as usize;
+                let value = ec.borrow().get_ref()[write_off];
+                ec.borrow_mut().get_mut()[write_off] = !value;
+                assert!(!writer.verify_speed(i).unwrap());
+
+                writer.write_speed_percent(i, 50.0).unwrap();
+            }
+
+            // The firmware overwrites the initialization registers.
+            let overwritten_registers: Vec<u8> = c
+                .fan_configurations
+                .iter()
+                .map(|f| f.write_register)
+                .chain(
+                    writer
+                        .on_write_reg_confs
+                        .iter()
+                        .flatten()
+                        .map(|r| r.register),
+                )
+                .collect();
+            let init_registers: Vec<u8> = writer
+                .init_reg_confs
+                .iter()
+                .flatten()
+                .map(|r| r.register)
+                .filter(|r| {
+                    let overwritten = overwritten_registers.contains(r)
+                        || (c.read_write_words
+                            && overwritten_registers.contains(&r.wrapping_sub(1)));
+                    !overwritten
+                })
+                .collect();
+            if let Some(&register) = init_registers.first() {
+                let value = ec.borrow().get_ref()[register as usize];
+                ec.borrow_mut().get_mut()[register as usize] = !value;
+                assert!(!writer.verify_init().unwrap());
+
+                writer.init_write().unwrap();
+                assert!(writer.verify_init().unwrap());
+            }
+        });
+    }
 }
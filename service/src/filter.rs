@@ -0,0 +1,200 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::collections::{HashMap, VecDeque};
+
+use crate::config::service::TempFilter;
+use crate::temp::Temperatures;
+
+/// Holds the state of a filter for a temperature source.
+#[derive(Debug)]
+struct FilterState {
+    filter: TempFilter,
+    average: Option<f64>,
+    window: VecDeque<f64>,
+}
+
+impl FilterState {
+    fn new(filter: TempFilter) -> Self {
+        FilterState {
+            filter,
+            average: None,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Push a new value and get the filtered one.
+    fn update(&mut self, value: f64) -> f64 {
+        match self.filter {
+            TempFilter::Ema { alpha } => {
+                let alpha = alpha.clamp(f64::EPSILON, 1.0);
+                let average = match self.average {
+                    Some(average) => average + alpha * (value - average),
+                    None => value,
+                };
+                self.average = Some(average);
+                average
+            }
+            TempFilter::Median { window } => {
+                self.push(value, window);
+                let mut values: Vec<f64> = self.window.iter().copied().collect();
+                values.sort_by(f64::total_cmp);
+
+                // Both indices are the same when the number of values is odd.
+                let len = values.len();
+                (values[(len - 1) / 2] + values[len / 2]) / 2.0
+            }
+            TempFilter::Max { window } => {
+                self.push(value, window);
+                self.window
+                    .iter()
+                    .copied()
+                    .fold(f64::NEG_INFINITY, f64::max)
+            }
+        }
+    }
+
+    /// Push a value to the window, removing the oldest ones to keep at most `size` values.
+    fn push(&mut self, value: f64, size: usize) {
+        self.window.push_back(value);
+        while self.window.len() > size.max(1) {
+            self.window.pop_front();
+        }
+    }
+}
+
+/// Applies the filters to the temperatures, keeping their state between the polls.
+#[derive(Debug, Default)]
+pub(crate) struct TempFilters {
+    states: HashMap<String, FilterState>,
+}
+
+impl TempFilters {
+    /// Filter the value of the source `name` if there is a filter for it.
+    fn filter(&mut self, filters: &HashMap<String, TempFilter>, name: &str, value: f64) -> f64 {
+        match filters.get(name) {
+            Some(&filter) => {
+                let state = self
+                    .states
+                    .entry(name.to_owned())
+                    .or_insert_with(|| FilterState::new(filter));
+                // The state is reset when the filter has been changed.
+                if state.filter != filter {
+                    *state = FilterState::new(filter);
+                }
+                state.update(value)
+            }
+            None => value,
+        }
+    }
+
+    /// Get the temperatures filtered with `filters`, which are keyed by temperature source.
+    pub fn apply(
+        &mut self,
+        temps: &Temperatures,
+        filters: &HashMap<String, TempFilter>,
+    ) -> Temperatures {
+        self.states.retain(|name, _| filters.contains_key(name));
+
+        Temperatures {
+            cpu_temp: self.filter(filters, "CPU", temps.cpu_temp),
+            cpu_source: temps.cpu_source.to_owned(),
+            gpu_temp: temps.gpu_temp.map(|t| self.filter(filters, "GPU", t)),
+            nvme_temp: temps.nvme_temp.map(|t| self.filter(filters, "NVME", t)),
+            acpi_temp: temps.acpi_temp.map(|t| self.filter(filters, "ACPI", t)),
+            sensors: temps
+                .sensors
+                .iter()
+                .map(|(name, &t)| (name.to_owned(), self.filter(filters, name, t)))
+                .collect(),
+            crits: temps.crits.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_values(filter: TempFilter, values: &[f64]) -> Vec<f64> {
+        let mut state = FilterState::new(filter);
+        values.iter().map(|&v| state.update(v)).collect()
+    }
+
+    #[test]
+    fn ema() {
+        assert_eq!(
+            filter_values(TempFilter::Ema { alpha: 0.5 }, &[40.0, 60.0, 60.0, 40.0]),
+            vec![40.0, 50.0, 55.0, 47.5]
+        );
+        assert_eq!(
+            filter_values(TempFilter::Ema { alpha: 1.0 }, &[40.0, 60.0, 45.0]),
+            vec![40.0, 60.0, 45.0]
+        );
+    }
+
+    #[test]
+    fn median() {
+        assert_eq!(
+            filter_values(
+                TempFilter::Median { window: 3 },
+                &[40.0, 90.0, 42.0, 44.0, 41.0]
+            ),
+            vec![40.0, 65.0, 42.0, 44.0, 42.0]
+        );
+    }
+
+    #[test]
+    fn max() {
+        assert_eq!(
+            filter_values(
+                TempFilter::Max { window: 2 },
+                &[40.0, 90.0, 42.0, 44.0, 41.0]
+            ),
+            vec![40.0, 90.0, 90.0, 44.0, 44.0]
+        );
+        assert_eq!(
+            filter_values(TempFilter::Max { window: 0 }, &[40.0, 90.0, 42.0]),
+            vec![40.0, 90.0, 42.0]
+        );
+    }
+
+    #[test]
+    fn apply_to_sources() {
+        let mut temp_filters = TempFilters::default();
+        let mut filters = HashMap::new();
+        filters.insert("CPU".to_owned(), TempFilter::Max { window: 2 });
+        filters.insert("nvme/Composite".to_owned(), TempFilter::Ema { alpha: 0.5 });
+
+        let mut temps = Temperatures {
+            cpu_temp: 80.0,
+            cpu_source: "coretemp".to_owned(),
+            gpu_temp: Some(50.0),
+            nvme_temp: None,
+            acpi_temp: None,
+            sensors: vec![
+                ("nvme/Composite".to_owned(), 40.0),
+                ("coretemp/Core 0".to_owned(), 80.0),
+            ]
+            .into_iter()
+            .collect(),
+            crits: HashMap::new(),
+        };
+        temp_filters.apply(&temps, &filters);
+
+        temps.cpu_temp = 50.0;
+        temps.gpu_temp = Some(40.0);
+        temps.sensors.insert("nvme/Composite".to_owned(), 50.0);
+        temps.sensors.insert("coretemp/Core 0".to_owned(), 50.0);
+        let filtered = temp_filters.apply(&temps, &filters);
+
+        assert_eq!(filtered.cpu_temp, 80.0);
+        assert_eq!(filtered.gpu_temp, Some(40.0));
+        assert_eq!(filtered.sensors["nvme/Composite"], 45.0);
+        assert_eq!(filtered.sensors["coretemp/Core 0"], 50.0);
+
+        // The state is reset when the filter changes.
+        filters.insert("CPU".to_owned(), TempFilter::Ema { alpha: 0.5 });
+        assert_eq!(temp_filters.apply(&temps, &filters).cpu_temp, 50.0);
+    }
+}
@@ -0,0 +1,398 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::{debug, info};
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{read_dir, read_link, read_to_string, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::constants::HWMON_PATH;
+
+#[derive(Debug)]
+/// A temperature input of a hwmon chip, which is kept open between the reads.
+pub(crate) struct HwmonSensor {
+    /// Name of the chip.
+    pub chip: String,
+    pub label: Option<String>,
+    /// Maximum temperature given by the chip, in degrees Celsius.
+    pub max: Option<f64>,
+    /// Critical temperature given by the chip, in degrees Celsius.
+    pub crit: Option<f64>,
+    /// Identifier of the sensor, formatted as `chip/label` or as `chip/tempN` when it has no label.
+    /// When several chips have the same sensor, the device (or the `hwmonN` directory) of the
+    /// ones found after the first is added to tell them apart, e.g. `nvme/Composite (nvme1)`.
+    pub name: String,
+    input: File,
+}
+
+impl HwmonSensor {
+    /// Read the temperature, in degrees Celsius.
+    pub fn read(&mut self) -> io::Result<f64> {
+        let mut buf = String::new();
+        self.input.seek(SeekFrom::Start(0))?;
+        self.input.read_to_string(&mut buf)?;
+
+        // The temperature is in millidegrees Celsius.
+        let temp = buf
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(temp / 1000.0)
+    }
+}
+
+#[derive(Debug)]
+/// Discovers the temperature sensors of the hwmon chips once and reads them on demand.
+/// The chips are discovered again when they change (e.g. on hotplug) or when a read fails.
+pub(crate) struct SensorRegistry {
+    /// Directory of the hwmon chips (usually `/sys/class/hwmon`).
+    root: PathBuf,
+    /// Entries of `root` at the last discovery.
+    entries: Vec<OsString>,
+    sensors: Vec<HwmonSensor>,
+    /// Names given to the sensors, by device and name without the device, which are kept
+    /// so that the sensors are not renamed when other chips appear or disappear.
+    names: HashMap<(String, String), String>,
+    rescan: bool,
+}
+
+impl Default for SensorRegistry {
+    fn default() -> Self {
+        SensorRegistry::new(*HWMON_PATH)
+    }
+}
+
+impl SensorRegistry {
+    /// Create a registry for the hwmon chips in `root`.
+    /// The sensors are discovered on the first refresh.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        SensorRegistry {
+            root: root.into(),
+            entries: Vec::new(),
+            sensors: Vec::new(),
+            names: HashMap::new(),
+            rescan: true,
+        }
+    }
+
+    /// Get the sensors discovered at the last refresh.
+    pub fn sensors(&self) -> &[HwmonSensor] {
+        &self.sensors
+    }
+
+    fn list_entries(&self) -> io::Result<Vec<OsString>> {
+        let mut entries = read_dir(&self.root)?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<OsString>>>()?;
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    /// Discover the sensors again if the chips have changed since the last discovery.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let entries = self.list_entries()?;
+        if self.rescan || entries != self.entries {
+            self.scan(entries);
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, entries: Vec<OsString>) {
+        let sensors: Vec<(String, HwmonSensor)> = entries
+            .iter()
+            .flat_map(|entry| {
+                let dir = self.root.join(entry);
+                let device = chip_device(&dir, entry);
+                discover_chip(&dir)
+                    .into_iter()
+                    .map(move |s| (device.to_owned(), s))
+            })
+            .collect();
+
+        self.sensors = sensors
+            .into_iter()
+            .map(|(device, mut sensor)| {
+                sensor.name = self.name_sensor(device, &sensor.name);
+                sensor
+            })
+            .collect();
+        self.entries = entries;
+        self.rescan = false;
+
+        info!(
+            "Found {} temperature sensors in `{}`",
+            self.sensors.len(),
+            self.root.display()
+        );
+    }
+
+    /// Get the name of the sensor `name` of `device`, which is the one given the first time the
+    /// sensor has been found. The device is added when another device already has the sensor.
+    fn name_sensor(&mut self, device: String, name: &str) -> String {
+        let taken = self
+            .names
+            .iter()
+            .any(|((d, n), _)| n == name && *d != device);
+        self.names
+            .entry((device, name.to_owned()))
+            .or_insert_with_key(|(device, name)| {
+                if taken {
+                    format!("{} ({})", name, device)
+                } else {
+                    name.to_owned()
+                }
+            })
+            .to_owned()
+    }
+
+    /// Read the sensors for which `wanted` returns `true`.
+    /// The sensors which cannot be read are skipped and the chips are discovered again on the next refresh.
+    pub fn read<F>(&mut self, mut wanted: F) -> Vec<(&HwmonSensor, f64)>
+    where
+        F: FnMut(&HwmonSensor) -> bool,
+    {
+        let rescan = &mut self.rescan;
+        self.sensors
+            .iter_mut()
+            .filter(|s| wanted(s))
+            .filter_map(|s| match s.read() {
+                Ok(temp) => Some((&*s, temp)),
+                Err(e) => {
+                    debug!("Could not read the sensor `{}`: {}", s.name, e);
+                    *rescan = true;
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Find the directory of the hwmon chip named `name` in `root` (usually `/sys/class/hwmon`).
+pub(crate) fn find_chip(root: &Path, name: &str) -> io::Result<PathBuf> {
+    let mut dirs = read_dir(root)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    dirs.sort_unstable();
+
+    dirs.into_iter()
+        .find(|dir| read_to_string(dir.join("name")).is_ok_and(|n| n.trim() == name))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "There is no hwmon chip named `{}` in `{}`",
+                    name,
+                    root.display()
+                ),
+            )
+        })
+}
+
+/// Get the name of the device of the hwmon chip in `dir` (e.g. `nvme0` or `0000:00:18.3`),
+/// or the name of the directory (`entry`) when it has no device.
+fn chip_device(dir: &Path, entry: &OsString) -> String {
+    read_link(dir.join("device"))
+        .ok()
+        .and_then(|d| d.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| entry.to_string_lossy().into_owned())
+}
+
+/// Get the temperature sensors of the hwmon chip in `dir`.
+fn discover_chip(dir: &Path) -> Vec<HwmonSensor> {
+    let chip = match read_to_string(dir.join("name")) {
+        Ok(name) => name.trim().to_owned(),
+        Err(_) => return Vec::new(),
+    };
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Could not list the inputs of `{}`: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut inputs: Vec<(u32, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let file_name = e.file_name().into_string().ok()?;
+            let index = file_name
+                .strip_prefix("temp")?
+                .strip_suffix("_input")?
+                .parse()
+                .ok()?;
+            Some((index, e.path()))
+        })
+        .collect();
+    inputs.sort_unstable_by_key(|&(index, _)| index);
+
+    inputs
+        .into_iter()
+        .filter_map(|(index, path)| {
+            let input = File::open(&path)
+                .map_err(|e| debug!("Could not open `{}`: {}", path.display(), e))
+                .ok()?;
+            let label = read_to_string(dir.join(format!("temp{}_label", index)))
+                .ok()
+                .map(|l| l.trim().to_owned())
+                .filter(|l| !l.is_empty());
+            let name = match &label {
+                Some(label) => format!("{}/{}", chip, label),
+                None => format!("{}/temp{}", chip, index),
+            };
+
+            Some(HwmonSensor {
+                chip: chip.to_owned(),
+                label,
+                max: read_limit(dir, index, "max"),
+                crit: read_limit(dir, index, "crit"),
+                name,
+                input,
+            })
+        })
+        .collect()
+}
+
+/// Read the limit `kind` (e.g. `max` or `crit`) of the temperature input at `index` in `dir`.
+fn read_limit(dir: &Path, index: u32, kind: &str) -> Option<f64> {
+    let limit = read_to_string(dir.join(format!("temp{}_{}", index, kind))).ok()?;
+    // The temperature is in millidegrees Celsius.
+    limit.trim().parse::<f64>().ok().map(|l| l / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn read_all(registry: &mut SensorRegistry) -> Vec<(String, f64)> {
+        registry
+            .read(|_| true)
+            .into_iter()
+            .map(|(s, t)| (s.name.to_owned(), t))
+            .collect()
+    }
+
+    #[test]
+    fn fake_sysfs() {
+        let mut registry = SensorRegistry::new("tests/hwmon");
+        registry.refresh().unwrap();
+
+        assert_eq!(
+            read_all(&mut registry),
+            vec![
+                ("coretemp/Package id 0".to_owned(), 45.0),
+                ("coretemp/Core 0".to_owned(), 43.0),
+                ("acpitz/temp1".to_owned(), 40.0),
+                ("nvme/Composite".to_owned(), 35.85),
+                ("it8728/temp1".to_owned(), 38.0),
+                ("it8728/temp3".to_owned(), 51.0),
+            ]
+        );
+
+        let acpi: Vec<f64> = registry
+            .read(|s| s.chip == "acpitz")
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect();
+        assert_eq!(acpi, vec![40.0]);
+
+        let sensors = registry.sensors();
+        assert_eq!(sensors[0].label.as_deref(), Some("Package id 0"));
+        assert_eq!(sensors[0].max, Some(80.0));
+        assert_eq!(sensors[0].crit, Some(100.0));
+        assert_eq!(sensors[2].label, None);
+        assert_eq!(sensors[2].max, None);
+        assert_eq!(sensors[3].crit, Some(84.85));
+
+        assert!(SensorRegistry::new("tests/inexistent").refresh().is_err());
+
+        assert_eq!(
+            find_chip(Path::new("tests/hwmon"), "nvme").unwrap(),
+            Path::new("tests/hwmon/hwmon2")
+        );
+        assert!(find_chip(Path::new("tests/hwmon"), "thinkpad").is_err());
+    }
+
+    #[test]
+    fn same_chips() {
+        let mut registry = SensorRegistry::new("tests/hwmon-duplicates");
+        registry.refresh().unwrap();
+
+        assert_eq!(
+            read_all(&mut registry),
+            vec![
+                ("nvme/Composite".to_owned(), 35.0),
+                ("nvme/Composite (nvme1)".to_owned(), 41.0),
+                ("coretemp/Package id 0".to_owned(), 45.0),
+                ("coretemp/Package id 0 (hwmon3)".to_owned(), 47.0),
+                ("acpitz/temp1".to_owned(), 40.0),
+            ]
+        );
+        // The chip is kept, so the sensors still belong to their category.
+        assert_eq!(registry.sensors()[1].chip, "nvme");
+    }
+
+    #[test]
+    fn hotplug() {
+        let root = std::env::temp_dir().join(format!("fancy-hwmon-{}", std::process::id()));
+        let chip = root.join("hwmon0");
+        create_dir_all(&chip).unwrap();
+        write(chip.join("name"), "acpitz\n").unwrap();
+        write(chip.join("temp1_input"), "40000\n").unwrap();
+
+        let mut registry = SensorRegistry::new(&root);
+        registry.refresh().unwrap();
+        assert_eq!(
+            read_all(&mut registry),
+            vec![("acpitz/temp1".to_owned(), 40.0)]
+        );
+
+        // The value is read again from the open file.
+        write(chip.join("temp1_input"), "42000\n").unwrap();
+        assert_eq!(
+            read_all(&mut registry),
+            vec![("acpitz/temp1".to_owned(), 42.0)]
+        );
+
+        let new_chip = root.join("hwmon1");
+        create_dir_all(&new_chip).unwrap();
+        write(new_chip.join("name"), "nvme\n").unwrap();
+        write(new_chip.join("temp1_input"), "35000\n").unwrap();
+        write(new_chip.join("temp1_label"), "Composite\n").unwrap();
+        registry.refresh().unwrap();
+        assert_eq!(registry.sensors().len(), 2);
+
+        remove_dir_all(&new_chip).unwrap();
+        registry.refresh().unwrap();
+        assert_eq!(
+            read_all(&mut registry),
+            vec![("acpitz/temp1".to_owned(), 42.0)]
+        );
+
+        // The sensor found first keeps its name when a chip with the same sensor appears.
+        let same_chip = root.join("hwmon2");
+        create_dir_all(&same_chip).unwrap();
+        write(same_chip.join("name"), "acpitz\n").unwrap();
+        write(same_chip.join("temp1_input"), "45000\n").unwrap();
+        registry.refresh().unwrap();
+        assert_eq!(
+            read_all(&mut registry),
+            vec![
+                ("acpitz/temp1".to_owned(), 42.0),
+                ("acpitz/temp1 (hwmon2)".to_owned(), 45.0),
+            ]
+        );
+
+        remove_dir_all(&chip).unwrap();
+        registry.refresh().unwrap();
+        assert_eq!(
+            read_all(&mut registry),
+            vec![("acpitz/temp1 (hwmon2)".to_owned(), 45.0)]
+        );
+
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -14,31 +14,48 @@ use once_cell::sync::Lazy;
 use signal_hook::{consts::SIGTERM, flag::register};
 use snafu::{ResultExt, Snafu};
 
-use std::fs::OpenOptions;
-use std::path::Path;
 use std::rc::Rc;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Mutex,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 mod bus;
+mod calibration;
 mod config;
 mod constants;
+mod custom_sensor;
 mod ec_control;
+mod filter;
+mod hwmon;
+mod platform_profile;
+mod power;
+mod schedule;
 mod state;
 mod temp;
 
 use bus::connection::create_dbus_conn;
-use config::service::{ECAccessMode, ServiceConfig, TempComputeMethod};
-use constants::{BUS_NAME_STR, CONTROL_CONFIGS_DIR_PATH, OBJ_PATH_STR};
-use ec_control::{ECManager, RawPort, RW};
-use state::State;
+use calibration::SensorChecks;
+use config::profile::Profile;
+use config::service::{
+    ECAccessMode, FanBackendSettings, FaultFallback, ServiceConfig, StallReaction,
+};
+use constants::{
+    ACPI_CALL_PATH, ACPI_FIRMWARE_PATH, BUS_NAME_STR, CONTROL_CONFIGS_DIR_PATH, HWMON_PATH,
+    OBJ_PATH_STR, POWER_SUPPLY_PATH, PROFILES_DIR_PATH, THINKPAD_FAN_PATH,
+};
+use custom_sensor::CustomSensors;
+use ec_control::{
+    open_ec, AcpiCallBackend, ECBackend, ECManager, FanBackend, FanHealth, HwmonBackend,
+    MultiECBackend, ThinkpadBackend,
+};
+use filter::TempFilters;
+use platform_profile::{read_platform_profile, write_platform_profile};
+use power::PowerSource;
+use state::{ProfileReason, State};
 use temp::Temperatures;
 
-const CRITICAL_INTERVAL: u8 = 10;
-
 static BUS_NAME: Lazy<BusName> = Lazy::new(|| BusName::new(BUS_NAME_STR).unwrap());
 static DBUS_PATH: Lazy<DBusPath> = Lazy::new(|| DBusPath::new(OBJ_PATH_STR).unwrap());
 
@@ -46,11 +63,8 @@ type Result<T> = std::result::Result<T, ServiceError>;
 
 #[derive(Debug, Snafu)]
 enum ServiceError {
-    #[snafu(display("An I/O error occured while opening EC `{}`: {}", dev_path.display(), source))]
-    OpenDev {
-        dev_path: &'static Path,
-        source: std::io::Error,
-    },
+    #[snafu(display("{}", source))]
+    OpenEC { source: ec_control::ProbeError },
 
     #[snafu(display("{}", source))]
     ECIO { source: ec_control::ECError },
@@ -65,6 +79,11 @@ enum ServiceError {
         source: config::nbfc_control::ControlConfigLoadError,
     },
 
+    #[snafu(display("{}", source))]
+    ProfileLoad {
+        source: config::profile::ProfileLoadError,
+    },
+
     #[snafu(display("{}", source))]
     Sensor { source: temp::SensorError },
 
@@ -102,21 +121,7 @@ fn main() -> Result<()> {
         })
         .context(ServiceConfigLoad {})?;
 
-    // We have to check if it's /dev/port because we have to "wrap" the file in this case.
-    let is_raw_port = service_config.ec_access_mode == ECAccessMode::RawPort;
-    let dev_path = service_config.ec_access_mode.to_path().clone();
-    let ec_dev = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(dev_path)
-        .context(OpenDev { dev_path })?;
-
-    // XXX: Sorry...
-    let ec_dev = if is_raw_port {
-        Box::from(RawPort::from(ec_dev)) as Box<dyn RW>
-    } else {
-        Box::from(ec_dev) as Box<dyn RW>
-    };
+    let (backend, ec_access_mode) = open_backend(&service_config)?;
 
     let state = Rc::from(State::from(service_config));
     state
@@ -124,16 +129,30 @@ fn main() -> Result<()> {
         .borrow_mut()
         .add_path(&CONTROL_CONFIGS_DIR_PATH)
         .context(ControlConfigLoad {})?;
+
+    let profile = Profile::load(&*PROFILES_DIR_PATH, &state.profile.borrow());
+    match profile {
+        Ok(profile) => {
+            state.active_profile.replace(profile);
+        }
+        Err(e) => {
+            error!("{}", e);
+            info!("Using no profile");
+            state.profile.replace(String::new());
+            state.user_profile.replace(String::new());
+        }
+    }
+
     let dbus_conn = create_dbus_conn(Rc::clone(&state)).context(DBus {})?;
 
-    let fan_config = get_fan_config(Rc::clone(&state), &dbus_conn)?;
+    let fan_config = get_fan_config(Rc::clone(&state), &dbus_conn, backend.default_config())?;
 
     state
         .fans_speeds
         .replace(vec![0.0; fan_config.fan_configurations.len()]);
 
     state.poll_interval.replace(fan_config.ec_poll_interval);
-    let ec_manager = ECManager::new(ec_dev);
+    let ec_manager = ECManager::new(backend);
 
     state.fans_names.replace(
         ec_manager
@@ -160,7 +179,10 @@ fn main() -> Result<()> {
         );
     }
 
-    state.ec_access_mode.replace(ECAccessMode::from(dev_path));
+    // The configured access mode is kept when the EC is not used.
+    if let Some(ec_access_mode) = ec_access_mode {
+        state.ec_access_mode.replace(ec_access_mode);
+    }
 
     {
         // We have to clone the references to move them to the closure.
@@ -175,54 +197,79 @@ fn main() -> Result<()> {
                     for (property, _val) in props.changed_properties {
                         match &*property {
                             "Config" => {
-                                let config = state.config.borrow();
-                                info!("Swapping configuration to '{}'", &*config);
-
-                                let mut target_fans_speeds = state.target_fans_speeds.borrow_mut();
-                                let old_target_fans_speeds = target_fans_speeds.clone();
-
-                                let config_loader = state.config_loader.borrow();
-                                let conf = match config_loader.load_control_config(&*config) {
-                                    Ok(c) => c,
-                                    Err(e) => {
-                                        error!(
-                                            "Error while swapping to `{}`: {}
-                                        Keeping old configuration",
-                                            &*config, e
-                                        );
-                                        return true;
-                                    }
-                                };
-                                state.poll_interval.replace(conf.ec_poll_interval);
+                                let config = state.config.borrow().to_owned();
+                                info!("Swapping configuration to '{}'", config);
 
                                 let mut ec_manager = ec_manager.lock().unwrap();
-                                if let Err(e) = ec_manager.refresh_control_config(conf) {
+                                if let Err(e) = refresh_control_config(&state, &mut ec_manager) {
                                     error!(
-                                        "Error while refreshing manager with config `{}`: {}
+                                        "Error while swapping to `{}`: {}
                                         Keeping old configuration",
-                                        &*config, e
+                                        config, e
                                     );
                                     return true;
-                                };
-
-                                let fans_count = ec_manager.fan_configs.len();
-
-                                state.fans_speeds.replace(vec![0.0; fans_count]);
-
-                                state.fans_names.replace(
-                                    ec_manager
-                                        .fan_configs
-                                        .iter()
-                                        .map(|f| f.name.to_string())
-                                        .collect(),
-                                );
-
-                                *target_fans_speeds = vec![0.0; fans_count];
-
-                                target_fans_speeds.splice(0..fans_count, old_target_fans_speeds);
+                                }
 
                                 // We remove the old config when there is no error
                                 state.old_config.take();
+                                state.user_config.replace(config);
+                            }
+                            "Profile" => {
+                                let profile = state.profile.borrow().to_owned();
+                                info!("Applying profile '{}'", profile);
+
+                                let mut ec_manager = ec_manager.lock().unwrap();
+                                if let Err(e) = refresh_control_config(&state, &mut ec_manager) {
+                                    error!(
+                                        "Error while applying profile `{}`: {}
+                                        Keeping old profile",
+                                        profile, e
+                                    );
+                                    if let Some((old_profile, active_profile, reason)) =
+                                        state.old_profile.take()
+                                    {
+                                        state.profile.replace(old_profile);
+                                        state.active_profile.replace(active_profile);
+                                        state.profile_reason.replace(reason);
+                                        if let Err(e) =
+                                            refresh_control_config(&state, &mut ec_manager)
+                                        {
+                                            error!("Error while restoring the old profile: {}", e);
+                                        }
+                                    }
+                                    return true;
+                                }
+                                state.old_profile.take();
+                                state.user_profile.replace(profile.clone());
+
+                                let settings = state.platform_profile_settings.borrow();
+                                if let Some(platform_profile) = settings
+                                    .platform_profile_for(&profile)
+                                    .filter(|_| settings.write)
+                                {
+                                    match write_platform_profile(
+                                        *ACPI_FIRMWARE_PATH,
+                                        platform_profile,
+                                    ) {
+                                        Ok(true) => {
+                                            info!(
+                                                "Writing platform profile '{}'",
+                                                platform_profile
+                                            );
+                                            state
+                                                .platform_profile
+                                                .replace(Some(platform_profile.to_owned()));
+                                        }
+                                        Ok(false) => error!(
+                                            "The platform profile `{}` is not supported",
+                                            platform_profile
+                                        ),
+                                        Err(e) => error!(
+                                            "Error while writing platform profile `{}`: {}",
+                                            platform_profile, e
+                                        ),
+                                    }
+                                }
                             }
                             _ => {}
                         }
@@ -241,13 +288,73 @@ fn main() -> Result<()> {
     main_loop(ec_manager, dbus_conn, state)
 }
 
-/// Get the fan configuration in the `state` if applicable, else blocks the process until a
-/// valid one is provided.
+/// Open the backend used to control the fans, with the way the EC is accessed
+/// (`None` if the backend does not use the EC).
+fn open_backend(
+    service_config: &ServiceConfig,
+) -> Result<(Box<dyn FanBackend>, Option<ECAccessMode>)> {
+    match &service_config.backend {
+        FanBackendSettings::Ec if service_config.ec_access_mode == ECAccessMode::AcpiCall => Ok((
+            Box::new(AcpiCallBackend::new(
+                *ACPI_CALL_PATH,
+                &service_config.acpi_call,
+            )),
+            Some(ECAccessMode::AcpiCall),
+        )),
+        FanBackendSettings::Ec => {
+            let (ec_dev, ec_access_mode) =
+                open_ec(service_config.ec_access_mode, &service_config.ec_devices)
+                    .context(OpenEC {})?;
+            if service_config.secondary_ecs.is_empty() {
+                return Ok((Box::new(ECBackend::new(ec_dev)), Some(ec_access_mode)));
+            }
+
+            let others = service_config
+                .secondary_ecs
+                .iter()
+                .map(|ec| {
+                    open_ec(ec.access_mode, &ec.devices)
+                        .map(|(dev, _)| (dev, ec.fans.clone()))
+                        .context(OpenEC {})
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok((
+                Box::new(MultiECBackend::new(ec_dev, others)),
+                Some(ec_access_mode),
+            ))
+        }
+        FanBackendSettings::Hwmon {
+            chip,
+            pwms,
+            max_rpm,
+        } => Ok((
+            Box::new(HwmonBackend::new(*HWMON_PATH, chip, pwms, *max_rpm)),
+            None,
+        )),
+        FanBackendSettings::Thinkpad {
+            watchdog,
+            max_rpm,
+            temperature_thresholds,
+        } => Ok((
+            Box::new(ThinkpadBackend::new(
+                *THINKPAD_FAN_PATH,
+                *watchdog,
+                *max_rpm,
+                temperature_thresholds.clone(),
+            )),
+            None,
+        )),
+    }
+}
+
+/// Get the fan configuration in the `state` if applicable, else the one of the backend
+/// (`backend_config`), else blocks the process until a valid one is provided.
 fn get_fan_config(
     state: Rc<State>,
     dbus_conn: &LocalConnection,
+    backend_config: Option<nbfc::FanControlConfigV2>,
 ) -> Result<nbfc::FanControlConfigV2> {
-    if state.config.borrow().trim().is_empty() {
+    if state.config.borrow().trim().is_empty() && backend_config.is_none() {
         // Blocking the process until a valid configuration is provided.
         loop {
             dbus_conn.process(Duration::from_millis(1000)).unwrap();
@@ -263,26 +370,165 @@ fn get_fan_config(
         }
     }
 
-    let fan_config = state.config.borrow();
-    let config_loader = state.config_loader.borrow();
+    load_fan_config(&state, backend_config)
+}
+
+/// Load the selected fan configuration, or `backend_config` if none is selected,
+/// with the active profile applied on it.
+fn load_fan_config(
+    state: &State,
+    backend_config: Option<nbfc::FanControlConfigV2>,
+) -> Result<nbfc::FanControlConfigV2> {
+    let selected = state.config.borrow();
+    let mut fan_config = match backend_config {
+        Some(fan_config) if selected.trim().is_empty() => {
+            info!("Using the fan control configuration of the backend");
+            fan_config
+        }
+        _ => state
+            .config_loader
+            .borrow()
+            .load_control_config(&*selected)
+            .context(ControlConfigLoad {})?,
+    };
+    state.active_profile.borrow().apply(&mut fan_config);
+
+    Ok(fan_config)
+}
+
+/// Reload the selected fan configuration with the active profile applied on it
+/// and refresh the manager and the `state` with it.
+fn refresh_control_config<B: FanBackend>(
+    state: &State,
+    ec_manager: &mut ECManager<B>,
+) -> Result<()> {
+    let conf = load_fan_config(state, ec_manager.backend_config())?;
+
+    let poll_interval = conf.ec_poll_interval;
+    ec_manager.refresh_control_config(conf).context(ECIO {})?;
+    state.poll_interval.replace(poll_interval);
+
+    let fans_count = ec_manager.fan_configs.len();
+
+    state.fans_speeds.replace(vec![0.0; fans_count]);
+
+    state.fans_names.replace(
+        ec_manager
+            .fan_configs
+            .iter()
+            .map(|f| f.name.to_string())
+            .collect(),
+    );
+
+    let mut target_fans_speeds = state.target_fans_speeds.borrow_mut();
+    let old_target_fans_speeds = target_fans_speeds.clone();
+
+    *target_fans_speeds = vec![0.0; fans_count];
+
+    target_fans_speeds.splice(0..fans_count, old_target_fans_speeds);
+
+    Ok(())
+}
+
+/// Switch to the `profile` and to the fan control `config` because of `reason`,
+/// keeping the current ones if they are `None` or if they cannot be loaded.
+fn switch_profile<B: FanBackend>(
+    state: &State,
+    ec_manager: &mut ECManager<B>,
+    profile: Option<String>,
+    config: Option<String>,
+    reason: ProfileReason,
+) -> Result<()> {
+    let old_profile = state.profile.borrow().to_owned();
+    let old_config = state.config.borrow().to_owned();
+
+    if let Some(profile) = profile {
+        let active_profile =
+            Profile::load(&*PROFILES_DIR_PATH, &profile).context(ProfileLoad {})?;
+        state.profile.replace(profile);
+        state.active_profile.replace(active_profile);
+    }
+    if let Some(config) = config {
+        state.config.replace(config);
+    }
+
+    if let Err(e) = refresh_control_config(state, ec_manager) {
+        state.config.replace(old_config);
+        state.profile.replace(old_profile);
+        state.active_profile.replace(
+            Profile::load(&*PROFILES_DIR_PATH, &state.profile.borrow()).unwrap_or_default(),
+        );
+        refresh_control_config(state, ec_manager)?;
+        return Err(e);
+    }
+
+    state.profile_reason.replace(reason);
 
-    config_loader
-        .load_control_config(&*fan_config)
-        .context(ControlConfigLoad {})
+    Ok(())
 }
 
-fn main_loop<T: RW>(
-    ec_manager: Rc<Mutex<ECManager<T>>>,
+/// Apply the profile of the schedule entry active now, or restore the one which was used before
+/// (stored in `profile_before_schedule`) when the entry ends.
+fn follow_schedule<B: FanBackend>(
+    state: &State,
+    ec_manager: &mut ECManager<B>,
+    profile_before_schedule: &mut Option<(String, ProfileReason)>,
+) -> Result<()> {
+    let active_schedule = schedule::local_time().and_then(|(day, time)| {
+        schedule::active_entry(&state.schedule.borrow(), day, time).cloned()
+    });
+    if active_schedule == *state.active_schedule.borrow() {
+        return Ok(());
+    }
+    state.active_schedule.replace(active_schedule.clone());
+
+    match active_schedule {
+        Some(entry) => {
+            info!("Entering schedule entry '{}'", entry);
+            if let Some(profile) = entry.profile.clone() {
+                if profile_before_schedule.is_none() {
+                    *profile_before_schedule = Some((
+                        state.profile.borrow().to_owned(),
+                        state.profile_reason.borrow().clone(),
+                    ));
+                }
+                let reason = ProfileReason::Schedule(entry.to_string());
+                return switch_profile(state, ec_manager, Some(profile), None, reason);
+            }
+        }
+        None => info!("Leaving schedule"),
+    }
+
+    // The profile is restored only if it has not been changed during the schedule.
+    if let Some((profile, reason)) = profile_before_schedule.take() {
+        if matches!(*state.profile_reason.borrow(), ProfileReason::Schedule(_)) {
+            return switch_profile(state, ec_manager, Some(profile), None, reason);
+        }
+    }
+
+    Ok(())
+}
+
+fn main_loop<B: FanBackend>(
+    ec_manager: Rc<Mutex<ECManager<B>>>,
     dbus_conn: LocalConnection,
     state: Rc<State>,
 ) -> Result<()> {
     let signal_received = Arc::new(AtomicBool::new(false));
     register(SIGTERM, Arc::clone(&signal_received)).context(Signal {})?;
 
+    let mut last_update = Instant::now();
+    let mut loop_state = LoopState::default();
+    // Number of consecutive iterations which failed.
+    let mut failures = 0u32;
+
     while !signal_received.load(Ordering::Relaxed) {
         // We should normally use a timer (or convert service to async?) to call the function at an interval but instead of losing time,
         // we treat the D-Bus requests.
-        let timeout = {
+        let fault_policy = *state.fault_policy.borrow();
+        let timeout = if failures > 0 {
+            fault_policy.retry_delay(failures)
+        } else {
             let t = ec_manager.lock().unwrap().poll_interval;
             if t > Duration::ZERO {
                 t
@@ -310,75 +556,316 @@ fn main_loop<T: RW>(
 
         let mut ec_manager = ec_manager.lock().unwrap();
 
-        // TODO: Find a way to optimize that
-        let current_temps = Temperatures::get_temps().context(Sensor {})?;
-        let mut state_temps = state.temps.borrow_mut();
-        current_temps.update_map(&mut state_temps);
-        debug!("Temperatures: {:#?}", state_temps);
-
-        let temp = match *state.temp_compute.borrow() {
-            TempComputeMethod::CPUOnly => current_temps.cpu_temp,
-            TempComputeMethod::AllSensors => {
-                let temp_values = state_temps.values();
-                temp_values.clone().sum::<f64>() / temp_values.len() as f64
+        let elapsed = last_update.elapsed();
+        last_update = Instant::now();
+
+        match control_fans(&state, &mut ec_manager, &mut loop_state, elapsed) {
+            Ok(()) => {
+                if failures > 0 {
+                    info!("The fans can be controlled again");
+                    failures = 0;
+                    state.fault.replace(String::new());
+                }
+                if state.degraded.replace(false) {
+                    info!("Leaving degraded mode");
+                    if fault_policy.fallback == FaultFallback::Firmware {
+                        if let Err(e) = ec_manager.init_ec() {
+                            error!("Error while initializing the EC again: {}", e);
+                        }
+                    }
+                }
             }
-        };
+            Err(e) => {
+                failures = failures.saturating_add(1);
+                error!("Error while controlling the fans: {}", e);
+                state.fault.replace(e.to_string());
+
+                if failures > fault_policy.max_retries {
+                    if !state.degraded.replace(true) {
+                        error!(
+                            "Entering degraded mode after {} failures, fallback: {:?}",
+                            failures, fault_policy.fallback
+                        );
+                    }
+                    // The fallback is applied at each failure in case the fans have been reset.
+                    apply_fault_fallback(&mut ec_manager, fault_policy.fallback);
+                }
+            }
+        }
+    }
 
-        debug!("Computed temperature: {}", temp);
+    // We exit the loop
+    info!("Exiting");
+    let mut ec_manager = ec_manager.lock().unwrap();
+    ec_manager.reset_ec(true).context(ECIO {})
+}
 
-        let critical_now = *state.critical.borrow();
-        let mut critical_temp = state.critical.borrow_mut();
+/// Stores the values kept between the iterations of the main loop.
+#[derive(Default)]
+struct LoopState {
+    custom_sensors: CustomSensors,
+    sensor_checks: SensorChecks,
+    temp_filters: TempFilters,
+    last_power_source: Option<PowerSource>,
+    profile_before_schedule: Option<(String, ProfileReason)>,
+}
 
-        *critical_temp = if !critical_now {
-            temp as u8 >= ec_manager.critical_temperature
-        } else {
-            ec_manager.critical_temperature.saturating_sub(temp as u8) <= CRITICAL_INTERVAL
-        };
-        debug!("Critical state: {}", *critical_temp);
+/// Apply the `fallback` of the fault policy while the fans cannot be controlled.
+fn apply_fault_fallback<B: FanBackend>(ec_manager: &mut ECManager<B>, fallback: FaultFallback) {
+    let res = match fallback {
+        FaultFallback::FullSpeed => {
+            (0..ec_manager.fan_configs.len()).try_for_each(|i| ec_manager.write_fan_speed(i, 100.0))
+        }
+        FaultFallback::Firmware => ec_manager.reset_ec(true),
+    };
+    if let Err(e) = res {
+        error!("Error while applying the fault fallback: {}", e);
+    }
+}
 
-        let mut fans_speeds = state.fans_speeds.borrow_mut();
+/// Read the sensors and the EC and write the speeds of the fans for one iteration
+/// of the main loop, `elapsed` after the previous one.
+fn control_fans<B: FanBackend>(
+    state: &State,
+    ec_manager: &mut ECManager<B>,
+    loop_state: &mut LoopState,
+    elapsed: Duration,
+) -> Result<()> {
+    let power_source = power::power_source(*POWER_SUPPLY_PATH).unwrap_or_else(|e| {
+        debug!("Could not get the power source: {}", e);
+        None
+    });
+    if power_source != loop_state.last_power_source {
+        loop_state.last_power_source = power_source;
+        let settings = power_source.map(|s| (s, state.power_policy.borrow().get(s).clone()));
+        if let Some((source, settings)) = settings {
+            if settings.profile.is_some() || settings.config.is_some() {
+                info!("Switching to the settings for {} power", source);
+                let reason = ProfileReason::PowerSource(source);
+                if let Err(e) =
+                    switch_profile(state, ec_manager, settings.profile, settings.config, reason)
+                {
+                    error!(
+                        "Error while applying the settings for {} power: {}",
+                        source, e
+                    );
+                }
+            }
+        }
+    }
 
-        for i in 0..ec_manager.fan_configs.len() {
-            fans_speeds[i] = ec_manager.read_fan_speed(i).context(ECIO {})?;
-            debug!(
-                "Fan speed for {} with index {}: {:#?}",
-                ec_manager.fan_configs[i].name, i, fans_speeds[i]
-            );
+    let platform_profile = read_platform_profile(*ACPI_FIRMWARE_PATH).unwrap_or_else(|e| {
+        debug!("Could not read the platform profile: {}", e);
+        None
+    });
+    if platform_profile != *state.platform_profile.borrow() {
+        state.platform_profile.replace(platform_profile.clone());
+        let profile = platform_profile.as_ref().and_then(|p| {
+            state
+                .platform_profile_settings
+                .borrow()
+                .profiles
+                .get(p)
+                .cloned()
+        });
+        if let (Some(platform_profile), Some(profile)) = (platform_profile, profile) {
+            info!("Following platform profile '{}'", platform_profile);
+            let reason = ProfileReason::PlatformProfile(platform_profile);
+            if let Err(e) = switch_profile(state, ec_manager, Some(profile), None, reason) {
+                error!("Error while following the platform profile: {}", e);
+            }
+        }
+    }
 
-            // If there is a target fan speed set by the user
-            let user_defined_speed =
-                !*state.auto.borrow() && state.target_fans_speeds.borrow().get(i).is_some();
+    if let Err(e) = follow_schedule(state, ec_manager, &mut loop_state.profile_before_schedule) {
+        error!("Error while following the schedule: {}", e);
+    }
 
-            if *critical_temp {
-                ec_manager.write_fan_speed(i, 100.0).context(ECIO {})?;
-            } else if user_defined_speed {
+    let fans_settings = state.fans_settings.borrow();
+    let temp_filters = state.temp_filters.borrow();
+    let temp_weights = state.temp_weights.borrow();
+    let wanted: Vec<&str> = fans_settings
+        .sensors()
+        .chain(temp_filters.keys().map(String::as_str))
+        .chain(temp_weights.keys().map(String::as_str))
+        .collect();
+    let calibration = state.calibration.borrow();
+    let plausibility = *state.plausibility.borrow();
+    let sensor_checks = &mut loop_state.sensor_checks;
+    let mut check = |name: &str, temp: f64| {
+        sensor_checks.check(name, temp, calibration.get(name), &plausibility)
+    };
+    let mut current_temps = Temperatures::read(
+        &mut state.sensor_registry.borrow_mut(),
+        &wanted,
+        &state.cpu_temp_fallback.borrow(),
+        |register| {
+            ec_manager
+                .read_register(register)
+                .map_err(|e| debug!("Could not read EC register {:#04x}: {}", register, e))
+                .ok()
+                .map(f64::from)
+        },
+        &mut check,
+    )
+    .context(Sensor {})?;
+    let custom_temps = loop_state
+        .custom_sensors
+        .read(&state.custom_sensors.borrow());
+    current_temps.sensors.extend(
+        custom_temps
+            .into_iter()
+            .filter_map(|(name, temp)| check(&name, temp).map(|t| (name, t))),
+    );
+    state
+        .sensor_faults
+        .replace(loop_state.sensor_checks.faults());
+    if current_temps.cpu_source != *state.cpu_temp_source.borrow() {
+        info!(
+            "Using `{}` for the CPU temperature",
+            current_temps.cpu_source
+        );
+        state
+            .cpu_temp_source
+            .replace(current_temps.cpu_source.to_owned());
+    }
+    let mut state_temps = state.temps.borrow_mut();
+    current_temps.update_map(&mut state_temps);
+    debug!("Temperatures: {:#?}", state_temps);
+
+    let filtered_temps = loop_state.temp_filters.apply(&current_temps, &temp_filters);
+    let mut state_filtered_temps = state.filtered_temps.borrow_mut();
+    filtered_temps.update_map(&mut state_filtered_temps);
+    debug!("Filtered temperatures: {:#?}", state_filtered_temps);
+
+    let temp_compute = *state.temp_compute.borrow();
+    // The critical state is determined with the raw temperatures to not delay it.
+    let temp = current_temps.compute(temp_compute, &temp_weights);
+
+    debug!("Computed temperature: {}", temp);
+
+    ec_manager.keep_alive().context(ECIO {})?;
+    if *state.verify_writes.borrow() {
+        ec_manager.verify_writes().context(ECIO {})?;
+        state.write_counters.replace(ec_manager.write_counters);
+    }
+
+    let mut fans_speeds = state.fans_speeds.borrow_mut();
+    let mut fans_health = state.fans_health.borrow_mut();
+    let stall_detection = *state.stall_detection.borrow();
+    fans_health.resize(ec_manager.fan_configs.len(), FanHealth::Ok);
+
+    for i in 0..ec_manager.fan_configs.len() {
+        fans_speeds[i] = ec_manager.read_fan_speed(i).context(ECIO {})?;
+        debug!(
+            "Fan speed for {} with index {}: {:#?}",
+            ec_manager.fan_configs[i].name, i, fans_speeds[i]
+        );
+
+        let health = ec_manager.update_fan_health(i, fans_speeds[i], &stall_detection, elapsed);
+        if health != fans_health[i] {
+            match health {
+                FanHealth::Ok => info!(
+                    "{} with index {} follows the requested speed again",
+                    ec_manager.fan_configs[i].name, i
+                ),
+                _ => error!(
+                    "{} with index {} does not follow the requested speed: {}",
+                    ec_manager.fan_configs[i].name, i, health
+                ),
+            }
+        }
+        fans_health[i] = health;
+    }
+
+    let fan_failure = fans_health.iter().any(|&h| h != FanHealth::Ok);
+
+    let critical_now = *state.critical.borrow();
+    let mut critical_temp = state.critical.borrow_mut();
+
+    *critical_temp = ec_manager.is_critical(temp, critical_now);
+    if fan_failure && stall_detection.reaction == StallReaction::Critical {
+        *critical_temp = true;
+    }
+    debug!("Critical state: {}", *critical_temp);
+
+    let max_speed = state
+        .active_schedule
+        .borrow()
+        .as_ref()
+        .and_then(|e| e.max_speed);
+
+    for i in 0..ec_manager.fan_configs.len() {
+        let fan_settings = fans_settings.get(i, &ec_manager.fan_configs[i].name);
+        let temp_source = &fan_settings.temp_source;
+        let fan_temp = filtered_temps
+            .source_temp(
+                temp_source,
+                fan_settings.temp_compute.unwrap_or(temp_compute),
+                fan_settings.temp_weights.as_ref().unwrap_or(&temp_weights),
+            )
+            .unwrap_or_else(|| {
                 debug!(
-                    "Target fan speed for {} with index {}: {}",
-                    ec_manager.fan_configs[i].name,
-                    i,
-                    state.target_fans_speeds.borrow()[i]
+                    "Temperature source `{}` is not available, using computed temperature",
+                    temp_source
+                );
+                temp
+            });
+        debug!(
+            "Temperature for {} with index {} from `{}`: {}",
+            ec_manager.fan_configs[i].name, i, temp_source, fan_temp
+        );
+
+        // If there is a target fan speed set by the user
+        let user_defined_speed =
+            !*state.auto.borrow() && state.target_fans_speeds.borrow().get(i).is_some();
+
+        // The critical mode bypasses the ramp.
+        if *critical_temp {
+            ec_manager.write_fan_speed(i, 100.0).context(ECIO {})?;
+            continue;
+        }
+
+        let target = if user_defined_speed {
+            debug!(
+                "Target fan speed for {} with index {}: {}",
+                ec_manager.fan_configs[i].name,
+                i,
+                state.target_fans_speeds.borrow()[i]
+            );
+            Some(state.target_fans_speeds.borrow()[i])
+        } else {
+            let value = ec_manager
+                .compute_fan_speed(i, fan_temp, &fan_settings, elapsed)
+                .map(|v| state.active_profile.borrow().clamp_speed(v));
+            if let Some(value) = value {
+                debug!(
+                    "Computed fan speed with {} mode: {}",
+                    fan_settings.control_mode, value
                 );
-                ec_manager
-                    .write_fan_speed(i, state.target_fans_speeds.borrow()[i])
-                    .context(ECIO {})?;
             }
-            // If the function returns `true`, the threshold has changed.
-            // Else, there is nothing to change.
-            else if ec_manager.refresh_fan_threshold(current_temps.cpu_temp, i) {
-                let threshold = ec_manager.fan_configs[i].current_threshold;
-                debug!("Selected threshold #{}", threshold);
-                let value = ec_manager.fan_configs[i].thresholds[threshold]
-                    .fan_speed
-                    .into();
-                debug!("Threshold fan speed: {}", value);
-
-                ec_manager.write_fan_speed(i, value).context(ECIO {})?;
+            value
+        };
+
+        // The speed is capped by the schedule, except in critical mode.
+        let target = target.map(|t| max_speed.map_or(t, |m| t.min(m)));
+
+        // The working fans compensate for the failing ones, bypassing the ramp like in critical mode.
+        if fan_failure
+            && stall_detection.reaction == StallReaction::RaiseOthers
+            && fans_health[i] == FanHealth::Ok
+        {
+            if target.is_some() {
+                ec_manager.fan_configs[i].target_speed = target;
             }
+            ec_manager.write_fan_speed(i, 100.0).context(ECIO {})?;
+            continue;
         }
+
+        ec_manager
+            .write_fan_speed_ramped(i, target, &fan_settings, elapsed)
+            .context(ECIO {})?;
     }
 
-    // We exit the loop
-    info!("Exiting");
-    let mut ec_manager = ec_manager.lock().unwrap();
-    ec_manager.reset_ec(true).context(ECIO {})
+    Ok(())
 }
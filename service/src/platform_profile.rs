@@ -0,0 +1,73 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::Path;
+
+/// Read the current platform profile from the ACPI firmware directory `root`
+/// (usually `/sys/firmware/acpi`).
+///
+/// Returns `None` if the platform does not support profiles.
+pub(crate) fn read_platform_profile<P: AsRef<Path>>(root: P) -> io::Result<Option<String>> {
+    match read_to_string(root.as_ref().join("platform_profile")) {
+        Ok(profile) => Ok(Some(profile.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write the platform `profile` in the ACPI firmware directory `root`,
+/// if it is one of the choices provided by the platform.
+///
+/// Returns whether the profile has been written.
+pub(crate) fn write_platform_profile<P: AsRef<Path>>(root: P, profile: &str) -> io::Result<bool> {
+    let root = root.as_ref();
+    let choices = read_to_string(root.join("platform_profile_choices"))?;
+    if !choices.split_whitespace().any(|c| c == profile) {
+        return Ok(false);
+    }
+
+    write(root.join("platform_profile"), profile)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn read_write() {
+        let root = std::env::temp_dir().join(format!("fancy-acpi-{}", std::process::id()));
+        create_dir_all(&root).unwrap();
+
+        assert_eq!(read_platform_profile(&root).unwrap(), None);
+        assert!(write_platform_profile(&root, "balanced").is_err());
+
+        write(
+            root.join("platform_profile_choices"),
+            "low-power balanced performance\n",
+        )
+        .unwrap();
+        write(root.join("platform_profile"), "balanced\n").unwrap();
+        assert_eq!(
+            read_platform_profile(&root).unwrap().as_deref(),
+            Some("balanced")
+        );
+
+        assert!(write_platform_profile(&root, "low-power").unwrap());
+        assert_eq!(
+            read_platform_profile(&root).unwrap().as_deref(),
+            Some("low-power")
+        );
+
+        assert!(!write_platform_profile(&root, "quiet").unwrap());
+        assert_eq!(
+            read_platform_profile(&root).unwrap().as_deref(),
+            Some("low-power")
+        );
+
+        remove_dir_all(&root).unwrap();
+    }
+}
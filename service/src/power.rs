@@ -0,0 +1,77 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::fmt;
+use std::fs::{read_dir, read_to_string};
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Describe the source which powers the computer.
+pub(crate) enum PowerSource {
+    Ac,
+    Battery,
+}
+
+impl fmt::Display for PowerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerSource::Ac => f.write_str("AC"),
+            PowerSource::Battery => f.write_str("Battery"),
+        }
+    }
+}
+
+/// Get the current power source from the power supplies in `root` (usually `/sys/class/power_supply`).
+///
+/// The computer is considered on AC when one of the mains adapters is online,
+/// and there is no power source if there is no mains adapter (e.g. on a desktop).
+/// The USB supplies which tell whether they are online (e.g. USB-C chargers) are mains adapters.
+/// The adapters whose state cannot be read are ignored.
+pub(crate) fn power_source<P: AsRef<Path>>(root: P) -> io::Result<Option<PowerSource>> {
+    let mut source = None;
+
+    for entry in read_dir(root)? {
+        let path = entry?.path();
+        match read_to_string(path.join("type")) {
+            Ok(t) if matches!(t.trim(), "Mains" | "USB") => {}
+            _ => continue,
+        }
+
+        match read_to_string(path.join("online")) {
+            Ok(online) if online.trim() == "1" => return Ok(Some(PowerSource::Ac)),
+            Ok(_) => source = Some(PowerSource::Battery),
+            Err(_) => continue,
+        }
+    }
+
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fake_sysfs() {
+        assert_eq!(
+            power_source("tests/power_supply/ac").unwrap(),
+            Some(PowerSource::Ac)
+        );
+        assert_eq!(
+            power_source("tests/power_supply/battery").unwrap(),
+            Some(PowerSource::Battery)
+        );
+        assert_eq!(power_source("tests/power_supply/desktop").unwrap(), None);
+        // A USB-C charger, with a mains adapter which has no state.
+        assert_eq!(
+            power_source("tests/power_supply/usb_c").unwrap(),
+            Some(PowerSource::Ac)
+        );
+        assert_eq!(
+            power_source("tests/power_supply/usb_c_unplugged").unwrap(),
+            Some(PowerSource::Battery)
+        );
+        assert!(power_source("tests/power_supply/inexistent").is_err());
+    }
+}
@@ -0,0 +1,257 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    /// Get the day before this one.
+    pub fn pred(self) -> Self {
+        match self {
+            Weekday::Mon => Weekday::Sun,
+            Weekday::Tue => Weekday::Mon,
+            Weekday::Wed => Weekday::Tue,
+            Weekday::Thu => Weekday::Wed,
+            Weekday::Fri => Weekday::Thu,
+            Weekday::Sat => Weekday::Fri,
+            Weekday::Sun => Weekday::Sat,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub(crate) enum TimeOfDayParseError {
+    #[snafu(display("`{}` is not a valid time, it should be formatted as `HH:MM`", time))]
+    InvalidTime { time: String },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+/// Time of the day, with a precision of a minute.
+pub(crate) struct TimeOfDay {
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    pub fn new(hours: u8, minutes: u8) -> Self {
+        TimeOfDay {
+            minutes: hours.min(23) as u16 * 60 + minutes.min(59) as u16,
+        }
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = TimeOfDayParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let time = s
+            .split_once(':')
+            .and_then(|(h, m)| Some((h.parse::<u8>().ok()?, m.parse::<u8>().ok()?)))
+            .filter(|&(h, m)| h < 24 && m < 60);
+
+        match time {
+            Some((h, m)) => Ok(TimeOfDay::new(h, m)),
+            None => Err(InvalidTime { time: s }.build()),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = TimeOfDayParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        t.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Describe a time range during which a profile or a speed cap is applied.
+pub(crate) struct ScheduleEntry {
+    #[serde(default)]
+    pub name: String,
+    /// Days on which the range starts, every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    /// End of the range, which can be on the next day if it is before the start.
+    pub end: TimeOfDay,
+    /// Name of the profile to apply.
+    pub profile: Option<String>,
+    /// Maximum speed of the fans, in percent.
+    pub max_speed: Option<f64>,
+}
+
+impl ScheduleEntry {
+    fn has_day(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Test if the entry is active on `day` at `time`.
+    pub fn is_active(&self, day: Weekday, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.has_day(day) && self.start <= time && time < self.end
+        } else {
+            (self.has_day(day) && time >= self.start)
+                || (self.has_day(day.pred()) && time < self.end)
+        }
+    }
+}
+
+impl fmt::Display for ScheduleEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.name.is_empty() {
+            return f.write_str(&self.name);
+        }
+
+        if !self.days.is_empty() {
+            let days: Vec<String> = self.days.iter().map(|d| format!("{:?}", d)).collect();
+            write!(f, "{} ", days.join(","))?;
+        }
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Get the current local day and time.
+pub(crate) fn local_time() -> Option<(Weekday, TimeOfDay)> {
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: `localtime_r` only writes in the provided structure, which is read only on success.
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        if libc::localtime_r(&now, tm.as_mut_ptr()).is_null() {
+            return None;
+        }
+        tm.assume_init()
+    };
+
+    let day = match tm.tm_wday {
+        0 => Weekday::Sun,
+        1 => Weekday::Mon,
+        2 => Weekday::Tue,
+        3 => Weekday::Wed,
+        4 => Weekday::Thu,
+        5 => Weekday::Fri,
+        6 => Weekday::Sat,
+        _ => return None,
+    };
+
+    Some((day, TimeOfDay::new(tm.tm_hour as u8, tm.tm_min as u8)))
+}
+
+/// Get the first entry of `schedule` which is active on `day` at `time`.
+pub(crate) fn active_entry(
+    schedule: &[ScheduleEntry],
+    day: Weekday,
+    time: TimeOfDay,
+) -> Option<&ScheduleEntry> {
+    schedule.iter().find(|e| e.is_active(day, time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(days: Vec<Weekday>, start: &str, end: &str) -> ScheduleEntry {
+        ScheduleEntry {
+            name: String::new(),
+            days,
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            profile: None,
+            max_speed: Some(40.0),
+        }
+    }
+
+    #[test]
+    fn parse_time() {
+        assert_eq!("09:30".parse::<TimeOfDay>().unwrap(), TimeOfDay::new(9, 30));
+        assert_eq!(TimeOfDay::new(7, 5).to_string(), "07:05");
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("12:60".parse::<TimeOfDay>().is_err());
+        assert!("noon".parse::<TimeOfDay>().is_err());
+    }
+
+    #[test]
+    fn office_hours() {
+        let e = entry(
+            vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            "09:00",
+            "18:00",
+        );
+
+        assert!(e.is_active(Weekday::Mon, TimeOfDay::new(9, 0)));
+        assert!(e.is_active(Weekday::Fri, TimeOfDay::new(17, 59)));
+        assert!(!e.is_active(Weekday::Fri, TimeOfDay::new(18, 0)));
+        assert!(!e.is_active(Weekday::Tue, TimeOfDay::new(8, 59)));
+        assert!(!e.is_active(Weekday::Sat, TimeOfDay::new(12, 0)));
+        assert_eq!(e.to_string(), "Mon,Tue,Wed,Thu,Fri 09:00-18:00");
+    }
+
+    #[test]
+    fn overnight() {
+        let e = entry(vec![Weekday::Fri], "22:00", "07:00");
+
+        assert!(e.is_active(Weekday::Fri, TimeOfDay::new(23, 0)));
+        assert!(e.is_active(Weekday::Sat, TimeOfDay::new(6, 59)));
+        assert!(!e.is_active(Weekday::Sat, TimeOfDay::new(7, 0)));
+        assert!(!e.is_active(Weekday::Fri, TimeOfDay::new(6, 0)));
+        assert!(!e.is_active(Weekday::Sat, TimeOfDay::new(23, 0)));
+
+        let every_day = entry(vec![], "22:00", "07:00");
+        assert!(every_day.is_active(Weekday::Mon, TimeOfDay::new(1, 0)));
+        assert!(every_day.is_active(Weekday::Sun, TimeOfDay::new(22, 0)));
+    }
+
+    #[test]
+    fn first_active_entry() {
+        let schedule = vec![
+            ScheduleEntry {
+                name: "Lunch".to_string(),
+                ..entry(vec![], "12:00", "13:00")
+            },
+            entry(vec![], "09:00", "18:00"),
+        ];
+
+        assert_eq!(
+            active_entry(&schedule, Weekday::Wed, TimeOfDay::new(12, 30)).map(|e| e.to_string()),
+            Some("Lunch".to_string())
+        );
+        assert_eq!(
+            active_entry(&schedule, Weekday::Wed, TimeOfDay::new(14, 0)).map(|e| e.to_string()),
+            Some("09:00-18:00".to_string())
+        );
+        assert!(active_entry(&schedule, Weekday::Wed, TimeOfDay::new(20, 0)).is_none());
+    }
+}
@@ -1,49 +1,162 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::calibration::SensorFault;
 use crate::config::nbfc_control::ControlConfigLoader;
-use crate::config::service::{ECAccessMode, ServiceConfig, TempComputeMethod};
+use crate::config::profile::Profile;
+use crate::config::service::{
+    AcpiCallSettings, CpuTempFallbacks, CustomSensor, ECAccessMode, ECDevices, FanBackendSettings,
+    FansSettings, FaultPolicy, PlatformProfileSettings, Plausibility, PowerPolicy, SecondaryEC,
+    SensorCalibration, ServiceConfig, StallDetection, TempComputeMethod, TempFilter,
+};
+use crate::ec_control::{FanHealth, WriteCounters};
+use crate::hwmon::SensorRegistry;
+use crate::power::PowerSource;
+use crate::schedule::ScheduleEntry;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Describe why the current profile and fan control configuration are used.
+pub(crate) enum ProfileReason {
+    /// They have been chosen by the user.
+    #[default]
+    Manual,
+    /// They have been applied when the computer switched to this power source.
+    PowerSource(PowerSource),
+    /// They have been applied for this ACPI platform profile.
+    PlatformProfile(String),
+    /// They have been applied for this schedule entry.
+    Schedule(String),
+}
+
+impl fmt::Display for ProfileReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileReason::Manual => f.write_str("Manual"),
+            ProfileReason::PowerSource(source) => write!(f, "{}", source),
+            ProfileReason::PlatformProfile(profile) => write!(f, "Platform profile ({})", profile),
+            ProfileReason::Schedule(entry) => write!(f, "Schedule ({})", entry),
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 /// This struct is shared between the **D-Bus** tree and the `main` function.
 /// It stores the current state of the information provided through **D-Bus** and is used to save
 /// the configuration.
 pub(crate) struct State {
+    /// Way the EC is accessed, or the configured one if the backend does not use the EC.
     pub ec_access_mode: RefCell<ECAccessMode>,
+    pub backend: RefCell<FanBackendSettings>,
+    pub ec_devices: RefCell<ECDevices>,
+    pub secondary_ecs: RefCell<Vec<SecondaryEC>>,
+    pub acpi_call: RefCell<AcpiCallSettings>,
     pub fans_speeds: RefCell<Vec<f64>>,
     pub target_fans_speeds: RefCell<Vec<f64>>,
     pub manual_set_target_speeds: RefCell<bool>,
     /// Used when an error occured while trying to change the configuration.
     pub old_config: RefCell<Option<String>>,
+    /// Used when an error occured while trying to apply a new profile.
+    pub old_profile: RefCell<Option<(String, Profile, ProfileReason)>>,
     pub auto: RefCell<bool>,
     pub critical: RefCell<bool>,
     pub config: RefCell<String>,
+    /// Fan control configuration chosen by the user, which is saved instead of `config`
+    /// since the latter can be switched automatically.
+    pub user_config: RefCell<String>,
     pub temps: RefCell<HashMap<String, f64>>,
+    pub sensor_registry: RefCell<SensorRegistry>,
+    pub filtered_temps: RefCell<HashMap<String, f64>>,
+    pub temp_filters: RefCell<HashMap<String, TempFilter>>,
     pub temp_compute: RefCell<TempComputeMethod>,
+    pub temp_weights: RefCell<HashMap<String, f64>>,
+    pub custom_sensors: RefCell<HashMap<String, CustomSensor>>,
+    pub calibration: RefCell<HashMap<String, SensorCalibration>>,
+    pub plausibility: RefCell<Plausibility>,
+    /// Sensors whose last reading has been rejected by the plausibility checks.
+    pub sensor_faults: RefCell<HashMap<String, SensorFault>>,
+    pub cpu_temp_fallback: RefCell<CpuTempFallbacks>,
+    /// Source of the CPU temperature in use.
+    pub cpu_temp_source: RefCell<String>,
     pub poll_interval: RefCell<u64>,
     pub fans_names: RefCell<Vec<String>>,
     pub check_control_config: RefCell<bool>,
     pub config_loader: RefCell<ControlConfigLoader>,
+    pub fans_settings: RefCell<FansSettings>,
+    pub profile: RefCell<String>,
+    /// Profile chosen by the user, which is saved instead of `profile`
+    /// since the latter can be switched automatically.
+    pub user_profile: RefCell<String>,
+    pub active_profile: RefCell<Profile>,
+    pub profile_reason: RefCell<ProfileReason>,
+    pub power_policy: RefCell<PowerPolicy>,
+    pub platform_profile_settings: RefCell<PlatformProfileSettings>,
+    /// Last platform profile read.
+    pub platform_profile: RefCell<Option<String>>,
+    pub schedule: RefCell<Vec<ScheduleEntry>>,
+    pub active_schedule: RefCell<Option<ScheduleEntry>>,
+    pub stall_detection: RefCell<StallDetection>,
+    pub fans_health: RefCell<Vec<FanHealth>>,
+    pub verify_writes: RefCell<bool>,
+    pub write_counters: RefCell<WriteCounters>,
+    pub fault_policy: RefCell<FaultPolicy>,
+    /// Set while the fans cannot be controlled and the fallback of the fault policy is applied.
+    pub degraded: RefCell<bool>,
+    /// Last error which prevented the control of the fans, empty if there is none.
+    pub fault: RefCell<String>,
 }
 impl From<ServiceConfig> for State {
     fn from(s: ServiceConfig) -> Self {
         State {
             ec_access_mode: RefCell::new(s.ec_access_mode),
+            backend: RefCell::new(s.backend),
+            ec_devices: RefCell::new(s.ec_devices),
+            secondary_ecs: RefCell::new(s.secondary_ecs),
+            acpi_call: RefCell::new(s.acpi_call),
             fans_speeds: RefCell::new(Vec::new()),
             target_fans_speeds: RefCell::new(s.target_fans_speeds),
             manual_set_target_speeds: RefCell::new(false),
             old_config: RefCell::new(None),
+            old_profile: RefCell::new(None),
             auto: RefCell::new(s.auto),
             critical: RefCell::new(false),
-            config: RefCell::new(s.selected_fan_config),
+            config: RefCell::new(s.selected_fan_config.clone()),
+            user_config: RefCell::new(s.selected_fan_config),
             temps: RefCell::new(HashMap::new()),
+            sensor_registry: RefCell::new(SensorRegistry::default()),
+            filtered_temps: RefCell::new(HashMap::new()),
+            temp_filters: RefCell::new(s.temp_filters),
             temp_compute: RefCell::new(s.temp_compute),
+            temp_weights: RefCell::new(s.temp_weights),
+            custom_sensors: RefCell::new(s.custom_sensors),
+            calibration: RefCell::new(s.calibration),
+            plausibility: RefCell::new(s.plausibility),
+            sensor_faults: RefCell::new(HashMap::new()),
+            cpu_temp_fallback: RefCell::new(s.cpu_temp_fallback),
+            cpu_temp_source: RefCell::new(String::new()),
             poll_interval: RefCell::new(0),
             fans_names: RefCell::new(Vec::new()),
             check_control_config: RefCell::new(false),
             config_loader: RefCell::new(ControlConfigLoader::new(false)),
+            fans_settings: RefCell::new(s.fans),
+            profile: RefCell::new(s.profile.clone()),
+            user_profile: RefCell::new(s.profile),
+            active_profile: RefCell::new(Profile::default()),
+            profile_reason: RefCell::new(ProfileReason::Manual),
+            power_policy: RefCell::new(s.power),
+            platform_profile_settings: RefCell::new(s.platform_profile),
+            platform_profile: RefCell::new(None),
+            schedule: RefCell::new(s.schedule),
+            active_schedule: RefCell::new(None),
+            stall_detection: RefCell::new(s.stall_detection),
+            fans_health: RefCell::new(Vec::new()),
+            verify_writes: RefCell::new(s.verify_writes),
+            write_counters: RefCell::new(WriteCounters::default()),
+            fault_policy: RefCell::new(s.fault_policy),
+            degraded: RefCell::new(false),
+            fault: RefCell::new(String::new()),
         }
     }
 }
@@ -53,9 +166,27 @@ impl State {
             ec_access_mode: *self.ec_access_mode.borrow(),
             auto: *self.auto.borrow(),
             target_fans_speeds: self.target_fans_speeds.borrow().to_owned(),
-            selected_fan_config: self.config.borrow().to_owned(),
+            selected_fan_config: self.user_config.borrow().to_owned(),
             temp_compute: *self.temp_compute.borrow(),
             check_control_config: *self.check_control_config.borrow(),
+            profile: self.user_profile.borrow().to_owned(),
+            verify_writes: *self.verify_writes.borrow(),
+            backend: self.backend.borrow().clone(),
+            ec_devices: self.ec_devices.borrow().clone(),
+            secondary_ecs: self.secondary_ecs.borrow().clone(),
+            acpi_call: self.acpi_call.borrow().clone(),
+            power: self.power_policy.borrow().clone(),
+            platform_profile: self.platform_profile_settings.borrow().clone(),
+            temp_weights: self.temp_weights.borrow().clone(),
+            calibration: self.calibration.borrow().clone(),
+            custom_sensors: self.custom_sensors.borrow().clone(),
+            temp_filters: self.temp_filters.borrow().clone(),
+            fans: self.fans_settings.borrow().clone(),
+            schedule: self.schedule.borrow().clone(),
+            stall_detection: *self.stall_detection.borrow(),
+            fault_policy: *self.fault_policy.borrow(),
+            plausibility: *self.plausibility.borrow(),
+            cpu_temp_fallback: self.cpu_temp_fallback.borrow().clone(),
         }
     }
 }
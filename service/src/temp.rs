@@ -1,10 +1,18 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
-use psutil::sensors;
+use log::debug;
 use snafu::Snafu;
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use crate::config::service::{CpuTempFallback, TempComputeMethod, TempSource};
+use crate::constants::THERMAL_PATH;
+use crate::custom_sensor::CUSTOM_CHIP;
+use crate::hwmon::{HwmonSensor, SensorRegistry};
 
 const CPU_SENSORS_NAMES: &[&str] = &["coretemp", "k10temp"];
 
@@ -13,9 +21,11 @@ const CPU_SENSORS_NAMES: &[&str] = &["coretemp", "k10temp"];
 const GPU_SENSORS_NAMES: &[&str] = &["amdgpu", "radeon", "nouveau"];
 const ACPI_SENSORS_NAMES: &[&str] = &["acpitz"];
 const NVME_SENSORS_NAMES: &[&str] = &["nvme"];
+/// Critical temperature of the sources which do not provide one.
+const DEFAULT_CRITICAL_TEMP: f64 = 100.0;
 #[derive(Debug, Snafu)]
 pub(crate) enum SensorError {
-    #[snafu(display("Could not get access to a CPU sensor"))]
+    #[snafu(display("Could not get access to a CPU sensor or to a fallback source"))]
     NoCPUSensorFound,
 }
 
@@ -23,74 +33,184 @@ pub(crate) enum SensorError {
 /// This structure holds temperatures of various sensors through simple categories.
 pub(crate) struct Temperatures {
     pub cpu_temp: f64,
+    /// Source of the CPU temperature, which is a fallback source when there is no CPU sensor.
+    pub cpu_source: String,
     pub gpu_temp: Option<f64>,
     //TODO: The following sensors should be implemented in another structure
     pub nvme_temp: Option<f64>,
     pub acpi_temp: Option<f64>,
+    /// Every sensor which has been read, identified by `chip/label`.
+    pub sensors: HashMap<String, f64>,
+    /// Critical temperatures of the categories and of the sensors, when they are known.
+    pub crits: HashMap<String, f64>,
 }
 
 impl Temperatures {
-    //TODO: Find a way to hold some references to the sensors to not have to refresh everytime.
-    //TODO: Manage errors
-    /// Get the current temperatures.
-    pub fn get_temps() -> Result<Self, SensorError> {
-        let temperatures = sensors::temperatures();
-
-        let cpu_sensors: Vec<f64> = temperatures
-            .iter()
-            .filter_map(|s| s.as_ref().ok())
-            .filter(|s| CPU_SENSORS_NAMES.iter().any(|&c| c.contains(s.unit())))
-            .map(|s| s.current().celsius() as f64)
-            .filter(|x| x.is_normal())
-            .collect();
-        if cpu_sensors.is_empty() {
-            return Err(SensorError::NoCPUSensorFound {});
+    /// Read the current temperatures from the sensors of `registry`.
+    /// Only the sensors of the categories, of the fallback chips and the sensors named in `wanted`
+    /// are read.
+    /// The sources of `fallbacks` are tried in order when there is no CPU sensor,
+    /// where `read_ec` reads a register of the EC.
+    /// `check` calibrates the reading of a sensor and returns `None` when it is not plausible.
+    pub fn read<F, C>(
+        registry: &mut SensorRegistry,
+        wanted: &[&str],
+        fallbacks: &[CpuTempFallback],
+        read_ec: F,
+        mut check: C,
+    ) -> Result<Self, SensorError>
+    where
+        F: FnMut(u8) -> Option<f64>,
+        C: FnMut(&str, f64) -> Option<f64>,
+    {
+        if let Err(e) = registry.refresh() {
+            debug!("Could not discover the hwmon sensors: {}", e);
         }
 
-        let gpu_sensors: Vec<f64> = temperatures
-            .iter()
-            .filter_map(|s| s.as_ref().ok())
-            .filter(|s| GPU_SENSORS_NAMES.iter().any(|&c| c.contains(s.unit())))
-            .map(|s| s.current().celsius() as f64)
-            .filter(|x| x.is_normal())
+        let categories = [
+            CPU_SENSORS_NAMES,
+            GPU_SENSORS_NAMES,
+            ACPI_SENSORS_NAMES,
+            NVME_SENSORS_NAMES,
+        ];
+        let readings: Vec<(&HwmonSensor, f64)> = registry
+            .read(|s| {
+                categories.iter().any(|c| c.contains(&s.chip.as_str()))
+                    || wanted.contains(&s.name.as_str())
+                    || fallbacks
+                        .iter()
+                        .any(|f| matches!(f, CpuTempFallback::Chip { name } if *name == s.chip))
+            })
+            .into_iter()
+            .filter(|(_, t)| t.is_normal())
+            .filter_map(|(s, t)| check(&s.name, t).map(|t| (s, t)))
             .collect();
 
-        let acpi_sensors: Vec<f64> = temperatures
+        let mut crits: HashMap<String, f64> = readings
             .iter()
-            .filter_map(|s| s.as_ref().ok())
-            .filter(|s| ACPI_SENSORS_NAMES.iter().any(|&c| c.contains(s.unit())))
-            .map(|s| s.current().celsius() as f64)
-            .filter(|x| x.is_normal())
+            .filter_map(|(s, _)| s.crit.map(|c| (s.name.to_owned(), c)))
             .collect();
+        let category_crits = [
+            ("CPU", CPU_SENSORS_NAMES),
+            ("GPU", GPU_SENSORS_NAMES),
+            ("ACPI", ACPI_SENSORS_NAMES),
+            ("NVME", NVME_SENSORS_NAMES),
+        ];
+        for (category, chips) in category_crits {
+            // The lowest limit of the sensors of the category is used.
+            let crit = readings
+                .iter()
+                .filter(|(s, _)| chips.contains(&s.chip.as_str()))
+                .filter_map(|(s, _)| s.crit)
+                .fold(None, |m: Option<f64>, c| Some(m.map_or(c, |m| m.min(c))));
+            if let Some(crit) = crit {
+                crits.insert(category.to_owned(), crit);
+            }
+        }
 
-        let nvme_sensors: Vec<f64> = temperatures
+        let cpu_chip = readings
             .iter()
-            .filter_map(|s| s.as_ref().ok())
-            .filter(|s| NVME_SENSORS_NAMES.iter().any(|&c| c.contains(s.unit())))
-            .map(|s| s.current().celsius() as f64)
-            .filter(|x| x.is_normal())
-            .collect();
+            .map(|(s, _)| s.chip.as_str())
+            .find(|c| CPU_SENSORS_NAMES.contains(c));
+        let (cpu_source, cpu_temp) = match cpu_chip.zip(chips_temp(&readings, CPU_SENSORS_NAMES)) {
+            Some((chip, temp)) => (chip.to_owned(), temp),
+            None => fallback_cpu_temp(fallbacks, &readings, *THERMAL_PATH, read_ec)
+                .ok_or(SensorError::NoCPUSensorFound {})?,
+        };
 
         Ok(Temperatures {
-            cpu_temp: cpu_sensors.iter().fold(0f64, |a, s| a + s) / cpu_sensors.len() as f64,
-            gpu_temp: if !gpu_sensors.is_empty() {
-                Some(gpu_sensors.iter().fold(0f64, |a, s| a + s) / gpu_sensors.len() as f64)
-            } else {
-                None
-            },
-            acpi_temp: if !acpi_sensors.is_empty() {
-                Some(acpi_sensors.iter().fold(0f64, |a, s| a + s) / acpi_sensors.len() as f64)
-            } else {
-                None
-            },
-            nvme_temp: if !nvme_sensors.is_empty() {
-                Some(nvme_sensors.iter().fold(0f64, |a, s| a + s) / nvme_sensors.len() as f64)
-            } else {
-                None
-            },
+            cpu_temp,
+            cpu_source,
+            gpu_temp: chips_temp(&readings, GPU_SENSORS_NAMES),
+            acpi_temp: chips_temp(&readings, ACPI_SENSORS_NAMES),
+            nvme_temp: chips_temp(&readings, NVME_SENSORS_NAMES),
+            sensors: readings
+                .iter()
+                .map(|(s, t)| (s.name.to_owned(), *t))
+                .collect(),
+            crits,
         })
     }
 
+    /// Get the temperatures of the categories which are available, with their names.
+    fn categories(&self) -> impl Iterator<Item = (&'static str, f64)> {
+        std::iter::once(("CPU", self.cpu_temp))
+            .chain(self.gpu_temp.map(|t| ("GPU", t)))
+            .chain(self.acpi_temp.map(|t| ("ACPI", t)))
+            .chain(self.nvme_temp.map(|t| ("NVME", t)))
+    }
+
+    /// Compute the temperature according to `method`,
+    /// where `weights` are the weights of the sources for the `WeightedAverage` method.
+    pub fn compute(&self, method: TempComputeMethod, weights: &HashMap<String, f64>) -> f64 {
+        match method {
+            TempComputeMethod::CPUOnly => self.cpu_temp,
+            TempComputeMethod::AllSensors => {
+                let temps: Vec<f64> = self.categories().map(|(_, t)| t).collect();
+                temps.iter().sum::<f64>() / temps.len() as f64
+            }
+            TempComputeMethod::Max => self
+                .categories()
+                .map(|(_, t)| t)
+                .fold(self.cpu_temp, f64::max),
+            TempComputeMethod::WeightedAverage => {
+                let (sum, total) = weights
+                    .iter()
+                    .filter(|(_, &w)| w > 0.0)
+                    .filter_map(|(name, &w)| {
+                        let source = name.parse().ok().filter(|s| *s != TempSource::Aggregate)?;
+                        self.source_temp(&source, method, weights)
+                            .map(|t| (t * w, w))
+                    })
+                    .fold((0.0, 0.0), |(sum, total), (t, w)| (sum + t, total + w));
+                if total > 0.0 {
+                    sum / total
+                } else {
+                    self.cpu_temp
+                }
+            }
+            TempComputeMethod::RelativeToCritical => {
+                // Each sensor is compared to its own critical temperature,
+                // and the CPU temperature is kept for the fallback sources.
+                let relative = |(name, t): &(&str, f64)| {
+                    t / self
+                        .crits
+                        .get(*name)
+                        .copied()
+                        .unwrap_or(DEFAULT_CRITICAL_TEMP)
+                };
+                self.sensors
+                    .iter()
+                    .map(|(name, &t)| (name.as_str(), t))
+                    .chain(std::iter::once(("CPU", self.cpu_temp)))
+                    .max_by(|a, b| {
+                        relative(a)
+                            .partial_cmp(&relative(b))
+                            .unwrap_or(Ordering::Equal)
+                    })
+                    .map_or(self.cpu_temp, |(_, t)| t)
+            }
+        }
+    }
+
+    /// Get the temperature provided by `source`, if it is available.
+    /// `method` and `weights` are used to compute the `Aggregate` source.
+    pub fn source_temp(
+        &self,
+        source: &TempSource,
+        method: TempComputeMethod,
+        weights: &HashMap<String, f64>,
+    ) -> Option<f64> {
+        match source {
+            TempSource::Aggregate => Some(self.compute(method, weights)),
+            TempSource::Cpu => Some(self.cpu_temp),
+            TempSource::Gpu => self.gpu_temp,
+            TempSource::Nvme => self.nvme_temp,
+            TempSource::Acpi => self.acpi_temp,
+            TempSource::Sensor(name) => self.sensors.get(name).copied(),
+        }
+    }
+
     pub fn update_map(&self, m: &mut HashMap<String, f64>) {
         m.insert("CPU".to_owned(), self.cpu_temp);
         if let Some(gpu_temp) = self.gpu_temp {
@@ -104,5 +224,278 @@ impl Temperatures {
         if let Some(nvme_temp) = self.nvme_temp {
             m.insert("NVME".to_owned(), nvme_temp);
         }
+
+        // The custom sensors are not exposed with the hwmon sensors.
+        for (name, &temp) in &self.sensors {
+            if name.split_once('/').map(|(chip, _)| chip) == Some(CUSTOM_CHIP) {
+                m.insert(name.to_owned(), temp);
+            }
+        }
+    }
+}
+
+/// Get the mean temperature of the sensors of the chips named `chips` in `readings`.
+fn chips_temp(readings: &[(&HwmonSensor, f64)], chips: &[&str]) -> Option<f64> {
+    let temps: Vec<f64> = readings
+        .iter()
+        .filter(|(s, _)| chips.contains(&s.chip.as_str()))
+        .map(|&(_, t)| t)
+        .collect();
+    if temps.is_empty() {
+        None
+    } else {
+        Some(temps.iter().sum::<f64>() / temps.len() as f64)
+    }
+}
+
+/// Get the temperature of the first source of `fallbacks` which is available, with its name.
+fn fallback_cpu_temp<P, F>(
+    fallbacks: &[CpuTempFallback],
+    readings: &[(&HwmonSensor, f64)],
+    thermal_root: P,
+    mut read_ec: F,
+) -> Option<(String, f64)>
+where
+    P: AsRef<Path>,
+    F: FnMut(u8) -> Option<f64>,
+{
+    fallbacks.iter().find_map(|fallback| match fallback {
+        CpuTempFallback::Acpi => {
+            chips_temp(readings, ACPI_SENSORS_NAMES).map(|t| ("ACPI".to_owned(), t))
+        }
+        CpuTempFallback::ThermalZone { zone } => thermal_zone_temp(thermal_root.as_ref(), *zone),
+        CpuTempFallback::Chip { name } => {
+            chips_temp(readings, &[name.as_str()]).map(|t| (name.to_owned(), t))
+        }
+        CpuTempFallback::EcRegister { register } => read_ec(*register)
+            .filter(|t| t.is_normal())
+            .map(|t| (format!("EC register {:#04x}", register), t)),
+    })
+}
+
+/// Read the temperature of the thermal `zone` in `root` (usually `/sys/class/thermal`),
+/// or the highest one if `zone` is `None`.
+fn thermal_zone_temp<P: AsRef<Path>>(root: P, zone: Option<u32>) -> Option<(String, f64)> {
+    let root = root.as_ref();
+    let read_zone = |name: &str| -> Option<f64> {
+        let temp = read_to_string(root.join(name).join("temp")).ok()?;
+        // The temperature is in millidegrees Celsius.
+        let temp = temp.trim().parse::<f64>().ok()? / 1000.0;
+        if temp.is_normal() {
+            Some(temp)
+        } else {
+            None
+        }
+    };
+
+    match zone {
+        Some(zone) => {
+            let name = format!("thermal_zone{}", zone);
+            read_zone(&name).map(|t| (name, t))
+        }
+        None => read_dir(root)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| name.starts_with("thermal_zone"))
+            .filter_map(|name| read_zone(&name).map(|t| (name, t)))
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::SensorChecks;
+    use crate::config::service::{Plausibility, SensorCalibration};
+
+    fn temperatures() -> Temperatures {
+        Temperatures {
+            cpu_temp: 60.0,
+            cpu_source: "coretemp".to_owned(),
+            gpu_temp: Some(50.0),
+            nvme_temp: Some(70.0),
+            acpi_temp: None,
+            sensors: vec![
+                ("coretemp/Core 0".to_owned(), 64.0),
+                ("nvme/Composite".to_owned(), 58.0),
+                ("custom/gpu".to_owned(), 80.0),
+            ]
+            .into_iter()
+            .collect(),
+            crits: vec![
+                ("CPU".to_owned(), 100.0),
+                ("NVME".to_owned(), 75.0),
+                ("coretemp/Core 0".to_owned(), 100.0),
+                ("nvme/Composite".to_owned(), 60.0),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn compute() {
+        let temps = temperatures();
+        let no_weights = HashMap::new();
+
+        assert_eq!(temps.compute(TempComputeMethod::CPUOnly, &no_weights), 60.0);
+        assert_eq!(
+            temps.compute(TempComputeMethod::AllSensors, &no_weights),
+            60.0
+        );
+        assert_eq!(temps.compute(TempComputeMethod::Max, &no_weights), 70.0);
+        // The NVMe sensor is at 97% of its own critical temperature,
+        // while the hotter sensors are at 64% and 80% (without critical temperature).
+        assert_eq!(
+            temps.compute(TempComputeMethod::RelativeToCritical, &no_weights),
+            58.0
+        );
+
+        let weights: HashMap<String, f64> = vec![
+            ("coretemp/Core 0".to_owned(), 3.0),
+            ("GPU".to_owned(), 1.0),
+            ("ACPI".to_owned(), 5.0),
+            ("Aggregate".to_owned(), 5.0),
+            ("invalid".to_owned(), 5.0),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            temps.compute(TempComputeMethod::WeightedAverage, &weights),
+            60.5
+        );
+        // The CPU temperature is used when there is no weight.
+        assert_eq!(
+            temps.compute(TempComputeMethod::WeightedAverage, &no_weights),
+            60.0
+        );
+    }
+
+    #[test]
+    fn categories() {
+        let mut registry = SensorRegistry::new("tests/hwmon");
+        let temps = Temperatures::read(
+            &mut registry,
+            &["it8728/temp3"],
+            &[],
+            |_| None,
+            |_, t| Some(t),
+        )
+        .unwrap();
+
+        assert_eq!(temps.cpu_temp, 44.0);
+        assert_eq!(temps.cpu_source, "coretemp");
+        assert_eq!(temps.gpu_temp, None);
+        assert_eq!(temps.acpi_temp, Some(40.0));
+        assert_eq!(temps.nvme_temp, Some(35.85));
+        assert_eq!(temps.sensors.get("it8728/temp3"), Some(&51.0));
+        assert_eq!(temps.sensors.get("it8728/temp1"), None);
+        assert_eq!(temps.sensors.len(), 5);
+        assert_eq!(temps.crits.get("CPU"), Some(&100.0));
+        assert_eq!(temps.crits.get("NVME"), Some(&84.85));
+        assert_eq!(temps.crits.get("nvme/Composite"), Some(&84.85));
+        assert_eq!(temps.crits.get("ACPI"), None);
+
+        // The rejected readings are not used.
+        let check = |name: &str, t: f64| match name {
+            "coretemp/Core 0" => None,
+            "acpitz/temp1" => Some(t - 5.0),
+            _ => Some(t),
+        };
+        let temps = Temperatures::read(&mut registry, &[], &[], |_| None, check).unwrap();
+        assert_eq!(temps.cpu_temp, 45.0);
+        assert_eq!(temps.acpi_temp, Some(35.0));
+        assert_eq!(temps.sensors.get("coretemp/Core 0"), None);
+
+        let mut registry = SensorRegistry::new("tests/inexistent");
+        let fallbacks = [CpuTempFallback::EcRegister { register: 0x58 }];
+        let temps = Temperatures::read(
+            &mut registry,
+            &[],
+            &fallbacks,
+            |_| Some(60.0),
+            |_, t| Some(t),
+        )
+        .unwrap();
+        assert_eq!(temps.cpu_temp, 60.0);
+        assert_eq!(temps.cpu_source, "EC register 0x58");
+        assert!(Temperatures::read(&mut registry, &[], &[], |_| None, |_, t| Some(t)).is_err());
+    }
+
+    #[test]
+    fn category_jump() {
+        let mut registry = SensorRegistry::new("tests/hwmon");
+        let mut checks = SensorChecks::default();
+        let plausibility = Plausibility::default();
+        let mut read = |checks: &mut SensorChecks, offset: f64| {
+            let calibration = SensorCalibration {
+                offset: Some(offset),
+                scale: None,
+            };
+            Temperatures::read(
+                &mut registry,
+                &[],
+                &[],
+                |_| None,
+                |name, t| {
+                    let calibration = name.starts_with("coretemp/").then_some(&calibration);
+                    checks.check(name, t, calibration, &plausibility)
+                },
+            )
+        };
+
+        assert_eq!(read(&mut checks, 0.0).unwrap().cpu_temp, 44.0);
+        // Every CPU sensor jumps at the same time, the last temperatures are kept.
+        let temps = read(&mut checks, 40.0).unwrap();
+        assert_eq!(temps.cpu_temp, 44.0);
+        assert_eq!(temps.cpu_source, "coretemp");
+        assert_eq!(checks.faults().len(), 2);
+        // The jump is confirmed.
+        assert_eq!(read(&mut checks, 40.0).unwrap().cpu_temp, 84.0);
+        assert!(checks.faults().is_empty());
+    }
+
+    #[test]
+    fn thermal_zones() {
+        assert_eq!(
+            thermal_zone_temp("tests/thermal", Some(0)),
+            Some(("thermal_zone0".to_owned(), 45.0))
+        );
+        assert_eq!(
+            thermal_zone_temp("tests/thermal", None),
+            Some(("thermal_zone1".to_owned(), 52.5))
+        );
+        assert_eq!(thermal_zone_temp("tests/thermal", Some(2)), None);
+        assert_eq!(thermal_zone_temp("tests/thermal", Some(3)), None);
+        assert_eq!(thermal_zone_temp("tests/inexistent", None), None);
+    }
+
+    #[test]
+    fn fallback_order() {
+        let fallbacks = vec![
+            CpuTempFallback::Chip {
+                name: "inexistent".to_owned(),
+            },
+            CpuTempFallback::ThermalZone { zone: Some(2) },
+            CpuTempFallback::EcRegister { register: 0x58 },
+            CpuTempFallback::ThermalZone { zone: None },
+        ];
+
+        let mut registers = Vec::new();
+        let temp = fallback_cpu_temp(&fallbacks, &[], "tests/thermal", |r| {
+            registers.push(r);
+            Some(60.0)
+        });
+        assert_eq!(temp, Some(("EC register 0x58".to_owned(), 60.0)));
+        assert_eq!(registers, vec![0x58]);
+
+        let temp = fallback_cpu_temp(&fallbacks, &[], "tests/thermal", |_| None);
+        assert_eq!(temp, Some(("thermal_zone1".to_owned(), 52.5)));
+
+        assert_eq!(
+            fallback_cpu_temp(&fallbacks[..2], &[], "tests/thermal", |_| None),
+            None
+        );
     }
 }